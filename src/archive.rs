@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entry, EntryType};
+
+use crate::digest::{self, Checksums, Digests};
+use crate::{create_decoder, Comp};
+
+/// Name of the manifest entry appended to the end of every archive
+pub const META: &str = "meta.json";
+
+/// Open archive for reading, detecting compression by its magic bytes
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive<Box<dyn Read>>> {
+    Ok(Archive::new(open_decompressed(path)?))
+}
+
+/// Open decompressed stream of tar data, detecting compression by its magic bytes
+pub fn open_decompressed<P: AsRef<Path>>(path: P) -> Result<Box<dyn Read>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let comp = Comp::detect(reader.fill_buf()?).with_context(|| {
+        format!(
+            "Unable to detect compression of '{}'",
+            path.as_os_str().to_string_lossy()
+        )
+    })?;
+    create_decoder(comp, reader)
+}
+
+/// Path as it is stored in the archive, i.e. without `./` components
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// Path an entry is unpacked to, relative to the target directory. Like `tar::Entry::unpack_in`
+/// it drops the root and `./` components, `None` if the path points to a parent with `..`
+pub fn unpack_path(path: &Path) -> Option<PathBuf> {
+    let mut unpacked = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => unpacked.push(part),
+            Component::ParentDir => return None,
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Some(unpacked)
+}
+
+/// Archived path as recorded in meta.json or found in the archive itself
+#[derive(Debug)]
+pub enum Record {
+    File(Checksums),
+    Symlink(PathBuf),
+    HardLink(PathBuf),
+}
+
+impl Record {
+    /// Read record of an archive entry, `None` for entries meta.json does not track
+    pub fn read<R: Read>(entry: &mut Entry<R>, digests: Digests) -> Result<Option<Record>> {
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_file() || entry_type.is_gnu_sparse() {
+            return Ok(Some(Record::File(digest::calculate(digests, entry)?)));
+        }
+        let target = || -> Result<PathBuf> {
+            let target = entry.link_name()?.context("Link without target")?;
+            Ok(target.into_owned())
+        };
+        match entry_type {
+            EntryType::Symlink => Ok(Some(Record::Symlink(target()?))),
+            EntryType::Link => Ok(Some(Record::HardLink(target()?))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Describe how `actual` differs from this expected record, if it does
+    pub fn mismatch(&self, actual: &Record) -> Option<String> {
+        match (self, actual) {
+            (Record::File(expected), Record::File(actual)) => digest::mismatch(expected, actual),
+            (Record::Symlink(expected), Record::Symlink(actual))
+            | (Record::HardLink(expected), Record::HardLink(actual)) => {
+                (expected != actual).then(|| {
+                    format!(
+                        "expected link to {}, got {}",
+                        expected.display(),
+                        actual.display()
+                    )
+                })
+            }
+            (expected, actual) => Some(format!(
+                "expected {}, got {}",
+                expected.kind(),
+                actual.kind()
+            )),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Record::File(_) => "file",
+            Record::Symlink(_) => "symlink",
+            Record::HardLink(_) => "hard link",
+        }
+    }
+}
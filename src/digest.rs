@@ -0,0 +1,215 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Read;
+
+/// Checksums of a single file, keyed by algorithm name
+pub type Checksums = BTreeMap<String, String>;
+
+/// Checksum algorithm recorded in meta.json
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum HashAlg {
+    Md5,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Display for HashAlg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            HashAlg::Md5 => "md5",
+            HashAlg::Sha256 => "sha256",
+            HashAlg::Sha512 => "sha512",
+            HashAlg::Blake3 => "blake3",
+        };
+        write!(f, "{}", repr)
+    }
+}
+
+impl HashAlg {
+    fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlg::Md5 => Box::new(md5::Context::new()),
+            HashAlg::Sha256 => Box::new(<sha2::Sha256 as sha2::Digest>::new()),
+            HashAlg::Sha512 => Box::new(<sha2::Sha512 as sha2::Digest>::new()),
+            HashAlg::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for md5::Context {
+    fn update(&mut self, data: &[u8]) {
+        self.consume(data)
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.compute())
+    }
+}
+
+impl Hasher for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data)
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", sha2::Digest::finalize(*self))
+    }
+}
+
+impl Hasher for sha2::Sha512 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data)
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", sha2::Digest::finalize(*self))
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+/// Computes checksums of the same data with several algorithms at once
+pub struct Digests {
+    hashers: Vec<(HashAlg, Box<dyn Hasher>)>,
+}
+
+impl Digests {
+    pub fn new(algs: &[HashAlg]) -> Self {
+        Digests {
+            hashers: algs.iter().map(|alg| (*alg, alg.hasher())).collect(),
+        }
+    }
+
+    /// Digests with every supported algorithm.
+    ///
+    /// Used when reading archives, since meta.json with the list of algorithms is stored last
+    pub fn all() -> Self {
+        Digests::new(HashAlg::value_variants())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    pub fn finish(self) -> Checksums {
+        self.hashers
+            .into_iter()
+            .map(|(alg, hasher)| (alg.to_string(), hasher.finish()))
+            .collect()
+    }
+}
+
+/// Reader which computes checksums of everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    digests: Digests,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, digests: Digests) -> Self {
+        HashingReader {
+            inner,
+            digests,
+            len: 0,
+        }
+    }
+
+    /// Number of bytes read so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn finish(self) -> Checksums {
+        self.digests.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digests.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Feed everything from `reader` into `digests`
+pub fn calculate<R: Read>(mut digests: Digests, mut reader: R) -> Result<Checksums> {
+    let mut buf = vec![0; 4194304];
+    let mut n = reader.read(&mut buf[..])?;
+    while n != 0 {
+        digests.update(&buf[..n]);
+        n = reader.read(&mut buf[..])?;
+    }
+    Ok(digests.finish())
+}
+
+/// Describe how checksums recorded in meta.json differ from actual ones, if they do.
+///
+/// Only algorithms present in both are compared, and a record with none of them fails, as it
+/// can't be checked at all
+pub fn mismatch(expected: &Checksums, actual: &Checksums) -> Option<String> {
+    let mut compared = false;
+    for (alg, hash) in expected {
+        match actual.get(alg) {
+            Some(actual) if actual != hash => {
+                return Some(format!("expected {} {}, got {}", alg, hash, actual))
+            }
+            Some(_) => compared = true,
+            None => {}
+        }
+    }
+    match compared {
+        true => None,
+        false if expected.is_empty() => Some("no checksums recorded".to_owned()),
+        false => Some(format!(
+            "no supported checksum algorithm, recorded {}",
+            expected.keys().cloned().collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksums(pairs: &[(&str, &str)]) -> Checksums {
+        pairs
+            .iter()
+            .map(|(alg, hash)| (alg.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn common_algorithms_are_compared() {
+        let actual = checksums(&[("md5", "a"), ("sha256", "b")]);
+        assert_eq!(mismatch(&checksums(&[("sha256", "b")]), &actual), None);
+        assert!(mismatch(&checksums(&[("md5", "a"), ("sha256", "c")]), &actual).is_some());
+    }
+
+    #[test]
+    fn records_without_known_algorithm_fail() {
+        let actual = checksums(&[("md5", "a")]);
+        assert!(mismatch(&checksums(&[]), &actual).is_some());
+        assert!(mismatch(&checksums(&[("sha1", "a")]), &actual).is_some());
+    }
+}
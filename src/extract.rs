@@ -0,0 +1,329 @@
+use anyhow::{bail, Context, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+use tar::{Archive, EntryType, Header};
+
+use crate::archive::{self, Record};
+use crate::digest::{self, Digests};
+use crate::manifest::{display_name, Manifest};
+use crate::xattrs::{self, XattrOptions};
+
+/// Reader which feeds every byte read through it into shared digests.
+///
+/// `tar::Archive` reads entry data straight from the underlying reader, so resetting
+/// digests before unpacking an entry yields checksums of exactly that entry
+struct HashTap<R> {
+    inner: R,
+    digests: Rc<RefCell<Digests>>,
+}
+
+impl<R: Read> Read for HashTap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digests.borrow_mut().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Unpack archive into `dir`, checking every file and link against meta.json.
+///
+/// meta.json is stored at the end of the archive, so files are checked once it is reached.
+/// Corrupted files and files missing from meta.json are removed, or moved into `quarantine` if it
+/// is set, and fail the extraction either way
+pub fn extract(
+    path: &Path,
+    dir: &Path,
+    quarantine: Option<&Path>,
+    xattr_options: XattrOptions,
+) -> Result<()> {
+    let digests = Rc::new(RefCell::new(Digests::all()));
+    let mut archive = Archive::new(HashTap {
+        inner: archive::open_decompressed(path)?,
+        digests: digests.clone(),
+    });
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    fs::create_dir_all(dir)?;
+
+    let mut actual: BTreeMap<PathBuf, Record> = BTreeMap::new();
+    let mut meta = None;
+    // Permissions and mtimes of directories are set once their contents are written and checked, so
+    // read-only directories can still be filled, and adding or removing files doesn't change their mtime
+    let mut directories = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        if archive::normalize(&entry_path) == Path::new(archive::META) {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            meta = Some(data);
+            continue;
+        }
+        // Everything done with the unpacked file uses this path, so it can't escape `dir`
+        let Some(path) = archive::unpack_path(&entry_path) else {
+            eprintln!("skipped:  {} (unsafe path)", display_name(&entry_path));
+            continue;
+        };
+        if is_special(entry.header().entry_type()) {
+            match unpack_special(entry.header(), dir, &path) {
+                Ok(()) => xattrs::restore(&mut entry, &dir.join(&path), xattr_options)?,
+                Err(err) => eprintln!("skipped:  {} ({})", display_name(&path), err),
+            }
+            continue;
+        }
+        if entry.header().entry_type().is_dir() {
+            directories.push((path, entry));
+            continue;
+        }
+        *digests.borrow_mut() = Digests::all();
+        if !entry.unpack_in(dir)? {
+            eprintln!("skipped:  {} (unsafe path)", display_name(&path));
+            continue;
+        }
+        xattrs::restore(&mut entry, &dir.join(&path), xattr_options)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_file() {
+            let hashes = digests.replace(Digests::all()).finish();
+            actual.insert(path, Record::File(hashes));
+        } else if entry_type.is_gnu_sparse() {
+            // Archive stream holds only data regions, the unpacked file has holes filled in
+            let hashes = digest::calculate(Digests::all(), File::open(dir.join(&path))?)?;
+            actual.insert(path, Record::File(hashes));
+        } else if let Some(record) = Record::read(&mut entry, Digests::new(&[]))? {
+            actual.insert(path, record);
+        }
+    }
+
+    let meta = meta.context("Archive does not contain meta.json")?;
+    // Paths with `..` are kept as they are, so they are reported missing
+    let expected: BTreeMap<_, _> = Manifest::parse(&meta)?
+        .records()?
+        .into_iter()
+        .map(|(path, record)| (archive::unpack_path(&path).unwrap_or(path), record))
+        .collect();
+
+    let mut failed = 0;
+    for (path, record) in &actual {
+        let (label, problem) = match expected.get(path) {
+            None => ("extra:   ", "not in meta.json".to_owned()),
+            Some(expected) => match expected.mismatch(record) {
+                Some(mismatch) => ("mismatch:", mismatch),
+                None => continue,
+            },
+        };
+        let file = dir.join(path);
+        match quarantine {
+            Some(quarantine) => {
+                let dst = quarantine.join(path);
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&file, &dst)?;
+                eprintln!(
+                    "{} {} ({}, moved to {})",
+                    label,
+                    display_name(path),
+                    problem,
+                    dst.display()
+                );
+            }
+            None => {
+                fs::remove_file(&file)?;
+                eprintln!("{} {} ({}, removed)", label, display_name(path), problem);
+            }
+        }
+        failed += 1;
+    }
+    for path in expected.keys().filter(|p| !actual.contains_key(*p)) {
+        eprintln!("missing:  {}", display_name(path));
+        failed += 1;
+    }
+
+    // Subdirectories go first, as their parents may not be writable anymore
+    directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (path, mut entry) in directories {
+        if !entry.unpack_in(dir)? {
+            eprintln!("skipped:  {} (unsafe path)", display_name(&path));
+            continue;
+        }
+        let dst = dir.join(&path);
+        xattrs::restore(&mut entry, &dst, xattr_options)?;
+        // `tar` leaves mtimes of directories as they are
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+        File::open(&dst)?.set_modified(mtime)?;
+    }
+
+    if failed != 0 {
+        bail!(
+            "Extraction failed: {} file(s) missing, corrupted or not in meta.json",
+            failed
+        );
+    }
+    Ok(())
+}
+
+fn is_special(entry_type: EntryType) -> bool {
+    matches!(
+        entry_type,
+        EntryType::Fifo | EntryType::Char | EntryType::Block
+    )
+}
+
+/// Create FIFO or device node, which `tar` unpacks as regular files
+fn unpack_special(header: &Header, dir: &Path, path: &Path) -> Result<()> {
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!("unsafe path");
+    }
+    let dst = dir.join(path);
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(dir.canonicalize()?) {
+            bail!("unsafe path");
+        }
+    }
+    if fs::symlink_metadata(&dst).is_ok_and(|meta| !meta.is_dir()) {
+        fs::remove_file(&dst)?;
+    }
+
+    let kind = match header.entry_type() {
+        EntryType::Fifo => libc::S_IFIFO,
+        EntryType::Char => libc::S_IFCHR,
+        _ => libc::S_IFBLK,
+    };
+    let dev = libc::makedev(
+        header.device_major()?.unwrap_or(0),
+        header.device_minor()?.unwrap_or(0),
+    );
+    let c_path = CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is a valid NUL-terminated string
+    if unsafe { libc::mknod(c_path.as_ptr(), kind | (header.mode()? & 0o7777), dev) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    /// Append file entry with `name` stored verbatim, which `tar::Builder` refuses for absolute paths
+    fn append_raw<W: io::Write>(tar: &mut tar::Builder<W>, name: &[u8], data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(EntryType::file());
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn directory_mode_and_mtime_are_set_after_its_contents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("dir.tar");
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_path("d").unwrap();
+        header.set_size(0);
+        header.set_mode(0o555);
+        header.set_mtime(1000);
+        header.set_entry_type(EntryType::dir());
+        header.set_cksum();
+        tar.append(&header, io::empty()).unwrap();
+        append_raw(&mut tar, b"d/f", b"data");
+        let meta = r#"{"timestamp": 0, "checksums": {"d/f": "8d777f385d3dfec8815d20f7496026dc"}}"#;
+        append_raw(&mut tar, archive::META.as_bytes(), meta.as_bytes());
+        tar.into_inner().unwrap();
+
+        let dir = tmp.path().join("out");
+        extract(&archive, &dir, None, XattrOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("d/f")).unwrap(), "data");
+        let meta = fs::metadata(dir.join("d")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o555);
+        assert_eq!(meta.mtime(), 1000);
+    }
+
+    #[test]
+    fn xattrs_of_read_only_file_are_restored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("xattrs.tar");
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        let xattr = format!("{}user.k", xattrs::PAX_PREFIX);
+        tar.append_pax_extensions([(xattr.as_str(), b"v".as_slice())])
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_path("f").unwrap();
+        header.set_size(4);
+        header.set_mode(0o444);
+        header.set_entry_type(EntryType::file());
+        header.set_cksum();
+        tar.append(&header, b"data".as_slice()).unwrap();
+        let meta = r#"{"timestamp": 0, "checksums": {"f": "8d777f385d3dfec8815d20f7496026dc"}}"#;
+        append_raw(&mut tar, archive::META.as_bytes(), meta.as_bytes());
+        tar.into_inner().unwrap();
+
+        let dir = tmp.path().join("out");
+        let options = XattrOptions {
+            xattrs: true,
+            acls: false,
+        };
+        extract(&archive, &dir, None, options).unwrap();
+        let file = dir.join("f");
+        assert_eq!(xattr::get(&file, "user.k").unwrap(), Some(b"v".to_vec()));
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o444);
+    }
+
+    #[test]
+    fn quarantined_and_extra_files_fail_extraction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("extra.tar");
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        append_raw(&mut tar, b"bad", b"data");
+        append_raw(&mut tar, b"evil", b"data");
+        let meta = r#"{"timestamp": 0, "checksums": {"bad": "00000000000000000000000000000000"}}"#;
+        append_raw(&mut tar, archive::META.as_bytes(), meta.as_bytes());
+        tar.into_inner().unwrap();
+
+        let dir = tmp.path().join("out");
+        let quarantine = tmp.path().join("quarantine");
+        let options = XattrOptions::default();
+        assert!(extract(&archive, &dir, Some(&quarantine), options).is_err());
+        assert!(!dir.join("bad").exists() && !dir.join("evil").exists());
+        assert!(quarantine.join("bad").exists() && quarantine.join("evil").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(extract(&archive, &dir, None, options).is_err());
+        assert!(!dir.join("bad").exists() && !dir.join("evil").exists());
+    }
+
+    #[test]
+    fn corrupted_absolute_entry_is_removed_inside_target_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let victim = tmp.path().join("victim");
+        fs::write(&victim, "precious").unwrap();
+        let archive = tmp.path().join("evil.tar");
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        append_raw(&mut tar, victim.as_os_str().as_bytes(), b"data");
+        let meta = format!(
+            r#"{{"timestamp": 0, "checksums": {{"{}": "00000000000000000000000000000000"}}}}"#,
+            victim.display()
+        );
+        append_raw(&mut tar, archive::META.as_bytes(), meta.as_bytes());
+        tar.into_inner().unwrap();
+
+        let dir = tmp.path().join("out");
+        assert!(extract(&archive, &dir, None, XattrOptions::default()).is_err());
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious");
+        let unpacked = dir.join(victim.strip_prefix("/").unwrap());
+        assert!(!unpacked.exists());
+    }
+}
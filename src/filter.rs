@@ -0,0 +1,94 @@
+//! Selection of paths to archive, applied while walking directories
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use ignore::Match;
+use std::path::{Path, PathBuf};
+
+/// Exclude and include patterns with .gitignore semantics.
+///
+/// Patterns are matched against paths as they are stored in the archive. A pattern with a slash
+/// anywhere but at its end is anchored to the start of the path, other patterns match at any depth
+pub struct Filter {
+    patterns: Gitignore,
+}
+
+impl Filter {
+    /// Build filter of `--exclude`, `--exclude-from` and `--include` patterns, `None` if there are none.
+    ///
+    /// Include patterns are added last, so like `!pattern` lines they take precedence over excludes
+    pub fn new(
+        excludes: &[String],
+        exclude_from: &[PathBuf],
+        includes: &[String],
+    ) -> Result<Option<Filter>> {
+        if excludes.is_empty() && exclude_from.is_empty() && includes.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in excludes {
+            builder
+                .add_line(None, pattern)
+                .with_context(|| format!("Invalid exclude pattern '{}'", pattern))?;
+        }
+        for path in exclude_from {
+            if let Some(err) = builder.add(path) {
+                return Err(err).with_context(|| {
+                    format!(
+                        "Unable to read exclude patterns from '{}'",
+                        path.as_os_str().to_string_lossy()
+                    )
+                });
+            }
+        }
+        for pattern in includes {
+            builder
+                .add_line(None, &format!("!{}", pattern))
+                .with_context(|| format!("Invalid include pattern '{}'", pattern))?;
+        }
+        Ok(Some(Filter {
+            patterns: builder.build()?,
+        }))
+    }
+
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Match<&Glob> {
+        self.patterns.matched(path, is_dir)
+    }
+}
+
+/// Ignore files honored by `--respect-ignore-files`, patterns of later ones take precedence
+const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".archiverignore"];
+
+/// Patterns of ignore files in `dir`, applying to everything under it. `None` if there are none
+pub fn read_ignore_files(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in IGNORE_FILES {
+        let path = dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        found = true;
+        // Invalid lines are reported, the rest of the file is still used
+        if let Some(err) = builder.add(&path) {
+            eprintln!(
+                "Invalid patterns in '{}': {}",
+                path.as_os_str().to_string_lossy(),
+                err
+            );
+        }
+    }
+    if !found {
+        return None;
+    }
+    match builder.build() {
+        Ok(patterns) => Some(patterns),
+        Err(err) => {
+            eprintln!(
+                "Unable to use ignore files of '{}': {}",
+                dir.as_os_str().to_string_lossy(),
+                err
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, Record};
+use crate::manifest::{self, Manifest};
+
+struct Item {
+    path: PathBuf,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+}
+
+/// Print every entry of the archive along with the data recorded in its meta.json
+pub fn list(path: &Path, as_json: bool) -> Result<()> {
+    let mut archive = archive::open(path)?;
+    let mut items = vec![];
+    let mut meta = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive::normalize(&entry.path()?);
+        if path == Path::new(archive::META) {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            meta = Some(data);
+            continue;
+        }
+        let header = entry.header();
+        items.push(Item {
+            path,
+            size: header.size()?,
+            mode: header.mode()? & 0o7777,
+            mtime: header.mtime()?,
+        });
+    }
+
+    let meta = meta.context("Archive does not contain meta.json")?;
+    let manifest = Manifest::parse(&meta)?;
+    let timestamp = manifest.timestamp;
+    let records = manifest.records()?;
+
+    if as_json {
+        let entries: Vec<_> = items
+            .iter()
+            .map(|item| {
+                let mut entry = json!({
+                    "path": manifest::display_name(&item.path),
+                    "size": item.size,
+                    "mode": item.mode,
+                    "mtime": item.mtime,
+                });
+                match records.get(&item.path) {
+                    Some(Record::File(hashes)) => entry["checksums"] = json!(hashes),
+                    Some(Record::Symlink(target) | Record::HardLink(target)) => {
+                        entry["link"] = json!(manifest::display_name(target))
+                    }
+                    None => {}
+                }
+                if let Some(bytes) = manifest::encode_bytes(&item.path) {
+                    entry["path_bytes"] = json!(bytes);
+                }
+                entry
+            })
+            .collect();
+        let out = json!({
+            "timestamp": timestamp,
+            "entries": entries,
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    println!("timestamp: {}", timestamp);
+    for item in items {
+        let (hashes, target) = match records.get(&item.path) {
+            Some(Record::File(hashes)) => {
+                let hashes = hashes
+                    .iter()
+                    .map(|(alg, hash)| format!("{}:{}", alg, hash))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (hashes, String::new())
+            }
+            Some(Record::Symlink(target)) => (
+                "-".to_owned(),
+                format!(" -> {}", manifest::display_name(target)),
+            ),
+            Some(Record::HardLink(target)) => (
+                "-".to_owned(),
+                format!(" link to {}", manifest::display_name(target)),
+            ),
+            None => ("-".to_owned(), String::new()),
+        };
+        println!(
+            "{:04o} {:>12} {:>10} {} {}{}",
+            item.mode,
+            item.size,
+            item.mtime,
+            hashes,
+            manifest::display_name(&item.path),
+            target
+        );
+    }
+    Ok(())
+}
@@ -1,98 +1,586 @@
-use anyhow::{bail, Result};
+mod archive;
+mod digest;
+mod extract;
+mod filter;
+mod list;
+mod manifest;
+mod owner;
+mod pipeline;
+mod sparse;
+mod stream;
+mod verify;
+mod walk;
+mod xattrs;
+
+use anyhow::{bail, Context, Result};
+use bzip2::read::BzDecoder;
 use bzip2::write::BzEncoder;
-use clap::{ArgAction, Parser, ValueEnum};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use digest::HashAlg;
+use filter::Filter;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use flate2::write::{GzEncoder, ZlibEncoder};
-use md5::Digest;
+use lz4_flex::frame::FrameEncoder;
+use manifest::{Entry, Manifest};
+use owner::{Id, Mode, Ownership};
+use pipeline::{Archived, Compressor, Encoder, Written};
 
-use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{self, File, Permissions};
+use std::io::{self, prelude::*, BufWriter};
+use std::ops::RangeInclusive;
+use std::os::fd::AsFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use std::{env, fs};
+use std::{env, thread};
+use stream::{Source, Stream};
 use tar::{EntryType, Header};
+use walk::WalkOptions;
+use xattrs::XattrOptions;
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
 
 /// Simple program to greet a person
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Files to add to archive
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Files to add to archive. `SRC=DST` stores SRC in the archive as DST
     #[arg(long, short, action = ArgAction::Set, num_args = 1..)]
     input: Vec<PathBuf>,
-    /// Path to archive. If omitted "archive.tar.<compression>" is created in current working directory
+    /// Also add files listed in this file, one per line, "-" to read the list from stdin
+    #[arg(long, short = 'T', value_name = "FILE")]
+    files_from: Option<PathBuf>,
+    /// Paths listed by --files-from are separated by NUL bytes instead of newlines
+    #[arg(long, requires = "files_from")]
+    null: bool,
+    /// Archive data read from stdin as NAME
+    #[arg(long, value_name = "NAME")]
+    stdin_as: Option<PathBuf>,
+    /// Archive output of a shell command as NAME. May be repeated
+    #[arg(long = "command", value_name = "NAME=COMMAND", value_parser = stream::parse_command)]
+    commands: Vec<(PathBuf, String)>,
+    /// Create byte-identical archives from identical inputs: sort entries, normalize owners and permissions,
+    /// and use SOURCE_DATE_EPOCH instead of current time, clamping mtimes to it
+    #[arg(long)]
+    reproducible: bool,
+    /// Directory input paths are relative to. Paths are stored in the archive relative to it
+    #[arg(long, short = 'C', value_name = "DIR")]
+    base_dir: Option<PathBuf>,
+    /// Store all paths in the archive under this directory
+    #[arg(long, value_name = "DIR")]
+    prefix: Option<PathBuf>,
+    /// Remove this many leading components from archived paths. Paths with no components left are not archived
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    strip_components: usize,
+    /// Path to archive, "-" to write it to stdout. If omitted "out.tar.<compression>" is created in current working directory
     #[arg(long, short)]
     output: Option<PathBuf>,
     /// Compression algorithm
     #[arg(long, short, value_enum, default_value_t = Comp::Bzip2)]
     compression: Comp,
+    /// Compression level. Defaults to the best compression supported by the algorithm
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+    /// Number of compression threads, 0 to use all available cores. Only zstd and xz support multithreading
+    #[arg(long)]
+    threads: Option<u32>,
+    /// Number of threads reading and hashing files. Defaults to the number of available cores
+    #[arg(long, short)]
+    jobs: Option<usize>,
+    /// Archive files symlinks point to instead of symlinks themselves
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Skip FIFOs and device nodes instead of archiving them
+    #[arg(long)]
+    skip_special: bool,
+    /// Skip paths matching this pattern, using .gitignore syntax. May be repeated
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Read exclude patterns from this file, one per line in .gitignore syntax. May be repeated
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+    /// Archive paths matching this pattern even if they match an exclude pattern. May be repeated
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Skip paths matched by .gitignore, .ignore and .archiverignore files of walked directories
+    #[arg(long)]
+    respect_ignore_files: bool,
+    /// Store extended attributes, including SELinux labels
+    #[arg(long)]
+    xattrs: bool,
+    /// Store POSIX ACLs
+    #[arg(long)]
+    acls: bool,
+    /// Store all entries as owned by this user: NAME, NAME:UID or UID
+    #[arg(long, value_name = "USER", value_parser = owner::parse_user)]
+    owner: Option<Id>,
+    /// Store all entries as owned by this group: NAME, NAME:GID or GID
+    #[arg(long, value_name = "GROUP", value_parser = owner::parse_group)]
+    group: Option<Id>,
+    /// Store only numeric user and group ids, without names
+    #[arg(long)]
+    numeric_owner: bool,
+    /// Map owners using this file of `SOURCE TARGET` lines, e.g. `alice app:1000` or `+1001 +0`
+    #[arg(long, value_name = "FILE")]
+    uid_map: Option<PathBuf>,
+    /// Map groups using this file of `SOURCE TARGET` lines, e.g. `staff app:1000` or `+1001 +0`
+    #[arg(long, value_name = "FILE")]
+    gid_map: Option<PathBuf>,
+    /// Change permissions of all entries, in chmod syntax, e.g. `go-w,a+rX` or `644`
+    #[arg(long, value_parser = Mode::parse)]
+    mode: Option<Mode>,
+    /// Checksum algorithms to record in meta.json
+    #[arg(long = "hash", value_enum, value_delimiter = ',', default_values_t = [HashAlg::Md5])]
+    hashes: Vec<HashAlg>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check archive contents against checksums stored in its meta.json
+    Verify {
+        /// Path to archive
+        archive: PathBuf,
+    },
+    /// Print archive contents along with checksums stored in its meta.json
+    List {
+        /// Path to archive
+        archive: PathBuf,
+        /// Print contents as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Unpack archive, validating every file against checksums stored in its meta.json
+    Extract {
+        /// Path to archive
+        archive: PathBuf,
+        /// Directory to unpack archive into
+        #[arg(long = "directory", short = 'C', default_value = ".")]
+        dir: PathBuf,
+        /// Move corrupted files and files missing from meta.json into this directory instead of removing them
+        #[arg(long)]
+        quarantine: Option<PathBuf>,
+        /// Restore extended attributes, including SELinux labels
+        #[arg(long)]
+        xattrs: bool,
+        /// Restore POSIX ACLs
+        #[arg(long)]
+        acls: bool,
+    },
+    /// Print JSON Schema of meta.json
+    Schema,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 enum Comp {
+    /// Plain tar without compression
+    None,
     Bzip2,
     Gzip,
     Zlib,
+    Zstd,
+    Xz,
+    Lz4,
 }
 
 impl Display for Comp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
+            Comp::None => "",
             Comp::Bzip2 => "bz2",
             Comp::Gzip => "gz",
             Comp::Zlib => "zlib",
+            Comp::Zstd => "zst",
+            Comp::Xz => "xz",
+            Comp::Lz4 => "lz4",
         };
         write!(f, "{}", repr)
     }
 }
 
+impl Comp {
+    /// Range of supported compression levels, if algorithm has any
+    fn levels(self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Comp::Bzip2 => Some(1..=9),
+            Comp::Gzip | Comp::Zlib | Comp::Xz => Some(0..=9),
+            Comp::Zstd => Some(zstd::compression_level_range()),
+            Comp::None | Comp::Lz4 => None,
+        }
+    }
+
+    fn best_level(self) -> Option<i32> {
+        match self {
+            Comp::Zstd => Some(19),
+            _ => self.levels().map(|levels| *levels.end()),
+        }
+    }
+
+    fn supports_threads(self) -> bool {
+        matches!(self, Comp::Zstd | Comp::Xz)
+    }
+
+    /// Name of the algorithm as accepted by `--compression`
+    fn name(self) -> String {
+        self.to_possible_value()
+            .expect("No skipped variants")
+            .get_name()
+            .to_owned()
+    }
+
+    /// Guess compression algorithm by the magic bytes at the start of an archive
+    fn detect(magic: &[u8]) -> Option<Comp> {
+        if magic.get(257..262) == Some(b"ustar") {
+            return Some(Comp::None);
+        }
+        match magic {
+            [b'B', b'Z', b'h', ..] => Some(Comp::Bzip2),
+            [0x1f, 0x8b, ..] => Some(Comp::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Comp::Zstd),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(Comp::Xz),
+            [0x04, 0x22, 0x4d, 0x18, ..] => Some(Comp::Lz4),
+            [cmf, flg, ..] if cmf & 0x0f == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 => {
+                Some(Comp::Zlib)
+            }
+            _ => None,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let mut output = cli
+    match cli.command {
+        Some(Command::Verify { ref archive }) => verify::verify(archive),
+        Some(Command::List { ref archive, json }) => list::list(archive, json),
+        Some(Command::Extract {
+            ref archive,
+            ref dir,
+            ref quarantine,
+            xattrs,
+            acls,
+        }) => extract::extract(
+            archive,
+            dir,
+            quarantine.as_deref(),
+            XattrOptions { xattrs, acls },
+        ),
+        Some(Command::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&Manifest::schema())?);
+            Ok(())
+        }
+        None => create(cli),
+    }
+}
+
+fn create(cli: Cli) -> Result<()> {
+    let output = cli
         .output
         .or_else(|| env::current_dir().ok())
         .expect("Unable to read current directory");
 
-    output = sanitize_path(output, cli.compression);
-    let all_files = resolve_paths(cli.input)?;
-    let mut hashes: HashMap<String, String> = HashMap::new();
-
-    let tar = File::create(output)?;
-    let enc = create_encoder(cli.compression, tar);
-    let mut tar = tar::Builder::new(enc);
-
-    for file_path in all_files {
-        let hash = calculate_md5(&file_path)?;
-        let mut file = File::open(&file_path)?;
-        tar.append_file(&file_path, &mut file)?;
-        hashes.insert(
-            file_path.as_os_str().to_string_lossy().into(),
-            format!("{:x}", hash),
+    let level = match (cli.level, cli.compression.levels()) {
+        (Some(level), Some(levels)) if !levels.contains(&level) => bail!(
+            "Compression level for {} must be in range {}..={}",
+            cli.compression.name(),
+            levels.start(),
+            levels.end()
+        ),
+        (Some(_), None) => bail!(
+            "{} does not support compression levels",
+            cli.compression.name()
+        ),
+        (level, _) => level.or(cli.compression.best_level()),
+    };
+    let threads = match cli.threads {
+        Some(_) if !cli.compression.supports_threads() => bail!(
+            "{} does not support multithreaded compression",
+            cli.compression.name()
+        ),
+        Some(0) => thread::available_parallelism()?.get() as u32,
+        Some(threads) => threads,
+        None => 1,
+    };
+
+    let jobs = match cli.jobs {
+        Some(jobs) => jobs.max(1),
+        None => thread::available_parallelism()?.get(),
+    };
+    if cli.stdin_as.is_some() && cli.files_from.as_deref() == Some(Path::new("-")) {
+        bail!("stdin can't be used both by --stdin-as and --files-from");
+    }
+    let mut input = cli.input;
+    if let Some(files_from) = &cli.files_from {
+        input.extend(walk::read_file_list(files_from, cli.null)?);
+    }
+    let inputs = walk::resolve_inputs(input, cli.base_dir.as_deref())?;
+    walk::check_paths(&inputs)?;
+    let prefix = match cli.prefix {
+        Some(prefix) => walk::relative_name(&prefix).with_context(|| {
+            format!(
+                "Prefix '{}' points outside of the archive",
+                prefix.as_os_str().to_string_lossy()
+            )
+        })?,
+        None => PathBuf::new(),
+    };
+    let sources = cli
+        .stdin_as
+        .map(|name| (name, Source::Stdin))
+        .into_iter()
+        .chain(
+            cli.commands
+                .into_iter()
+                .map(|(name, command)| (name, Source::Command(command))),
         );
+    let streams = sources
+        .map(|(name, source)| Stream::new(&name, source, &prefix))
+        .collect::<Result<Vec<_>>>()?;
+    let source_date_epoch = match cli.reproducible {
+        true => source_date_epoch()?,
+        false => None,
+    };
+    let timestamp = match cli.reproducible {
+        true => source_date_epoch.unwrap_or(0),
+        false => current_time(),
+    };
+    let filter = Filter::new(&cli.exclude, &cli.exclude_from, &cli.include)?;
+    let ownership = Ownership::new(
+        cli.owner,
+        cli.group,
+        match &cli.uid_map {
+            Some(path) => owner::read_uid_map(path)?,
+            None => HashMap::new(),
+        },
+        match &cli.gid_map {
+            Some(path) => owner::read_gid_map(path)?,
+            None => HashMap::new(),
+        },
+        cli.numeric_owner,
+        cli.mode,
+    );
+    let mut algs = cli.hashes;
+    algs.sort();
+    algs.dedup();
+    let mut entries = BTreeMap::new();
+    let mut total_files = 0;
+    let mut total_bytes = 0;
+
+    // Archive is written next to the output and renamed over it once complete, so an existing
+    // output is never truncated, even if it is a hard link to one of the inputs
+    let mut partial = None;
+    let mut replaced = None;
+    let (tar, output_id): (Output, _) = if output == Path::new("-") {
+        let stdout = io::stdout();
+        // Stdout may be redirected into a file under one of the inputs
+        let id = walk::file_id(&File::from(stdout.as_fd().try_clone_to_owned()?))?;
+        (Box::new(BufWriter::new(stdout)), id)
+    } else {
+        let output = sanitize_path(output, cli.compression);
+        // Existing output under one of the inputs is only replaced once the archive is complete
+        replaced = walk::check_output(&inputs, &output)?.and_then(|path| {
+            let meta = fs::metadata(&path).ok()?;
+            Some((path, (meta.dev(), meta.ino())))
+        });
+        let dir = match output.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let temp = tempfile::Builder::new()
+            .permissions(Permissions::from_mode(0o666))
+            .tempfile_in(dir)?;
+        let file = temp.reopen()?;
+        let id = walk::file_id(&file)?;
+        partial = Some((temp, output));
+        (Box::new(file), id)
+    };
+
+    let enc = create_encoder(cli.compression, level, threads, tar)?;
+    let mut tar = tar::Builder::new(Compressor::spawn(enc));
+
+    let options = pipeline::Options {
+        algs,
+        jobs,
+        walk: WalkOptions {
+            follow_symlinks: cli.follow_symlinks,
+            skip_special: cli.skip_special,
+            filter,
+            respect_ignore_files: cli.respect_ignore_files,
+            output: Some(output_id),
+            replaced,
+            strip_components: cli.strip_components,
+            prefix: prefix.clone(),
+            sort: cli.reproducible,
+        },
+        reproducible: cli.reproducible,
+        clamp_mtime: source_date_epoch,
+        xattrs: XattrOptions {
+            xattrs: cli.xattrs,
+            acls: cli.acls,
+        },
+        ownership,
+    };
+    let mut archived = HashSet::new();
+    let mut add_entry = |written: Written, source: Option<&Source>| -> Result<()> {
+        let mut entry = Entry::new(&written)?;
+        if matches!(written.archived, Archived::File(_)) {
+            total_files += 1;
+            total_bytes += entry.size.unwrap_or(0);
+        }
+        match source {
+            Some(Source::Stdin) => entry.source = Some(manifest::Source::Stdin),
+            Some(Source::Command(command)) => {
+                entry.source = Some(manifest::Source::Command);
+                entry.command = Some(command.clone());
+            }
+            None => {}
+        }
+        let name = manifest::display_name(&written.path);
+        if entries.insert(name.clone(), entry).is_some() {
+            bail!("'{}' would be recorded in meta.json more than once", name);
+        }
+        Ok(())
+    };
+    for written in pipeline::run(&mut tar, inputs, &options)? {
+        archived.insert(written.path.clone());
+        add_entry(written, None)?;
+    }
+    for stream in &streams {
+        if !archived.insert(stream.name.clone()) {
+            bail!(
+                "'{}' would be archived more than once",
+                stream.name.as_os_str().to_string_lossy()
+            );
+        }
+        let written = stream::append(&mut tar, stream, &options, timestamp)?;
+        add_entry(written, Some(&stream.source))?;
     }
-    let meta = json!({
-        "timestamp": current_time(),
-        "checksums": hashes
-    });
+    // Host and command line differ between otherwise identical builds
+    let (hostname, command_line) = match cli.reproducible {
+        true => (None, None),
+        false => (
+            hostname(),
+            Some(
+                env::args_os()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+    };
+    let meta = Manifest {
+        schema_version: manifest::SCHEMA_VERSION,
+        version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        hostname,
+        command_line,
+        timestamp,
+        compression: Some(cli.compression.name().to_owned()),
+        level,
+        algorithms: options.algs.iter().map(HashAlg::to_string).collect(),
+        total_files,
+        total_bytes,
+        entries,
+    };
     let data = serde_json::to_vec(&meta)?;
     tar.append(
-        &create_header("meta.json", data.len() as u64)?,
+        &create_header("meta.json", data.len() as u64, timestamp)?,
         data.as_slice(),
     )?;
-    tar.finish()?;
+    tar.into_inner()?.finish()?;
+    if let Some((temp, output)) = partial {
+        temp.persist(output)?;
+    }
     Ok(())
 }
 
-fn create_encoder(comp: Comp, file: File) -> Box<dyn Write> {
-    match comp {
-        Comp::Bzip2 => Box::new(BzEncoder::new(file, bzip2::Compression::best())),
-        Comp::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::best())),
-        Comp::Zlib => Box::new(ZlibEncoder::new(file, flate2::Compression::best())),
+/// Archive file or stdout
+type Output = Box<dyn Write + Send>;
+
+impl Encoder for Output {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
     }
 }
 
-fn create_header<P: AsRef<Path>>(path: P, size: u64) -> Result<Header> {
+macro_rules! impl_encoder {
+    ($($encoder:ty),*) => {$(
+        impl Encoder for $encoder {
+            fn finish(self: Box<Self>) -> io::Result<()> {
+                <$encoder>::finish(*self)?.flush()
+            }
+        }
+    )*};
+}
+
+impl_encoder!(
+    BzEncoder<Output>,
+    GzEncoder<Output>,
+    ZlibEncoder<Output>,
+    XzEncoder<Output>,
+    zstd::Encoder<'static, Output>
+);
+
+impl Encoder for FrameEncoder<Output> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        FrameEncoder::finish(*self)
+            .map_err(io::Error::other)?
+            .flush()
+    }
+}
+
+/// Create encoder for `comp`. `level` must be validated against [`Comp::levels`]
+fn create_encoder(
+    comp: Comp,
+    level: Option<i32>,
+    threads: u32,
+    file: Output,
+) -> Result<Box<dyn Encoder>> {
+    let level = level.unwrap_or_default();
+    let enc: Box<dyn Encoder> = match comp {
+        Comp::None => Box::new(file),
+        Comp::Bzip2 => Box::new(BzEncoder::new(file, bzip2::Compression::new(level as u32))),
+        Comp::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::new(level as u32))),
+        Comp::Zlib => Box::new(ZlibEncoder::new(
+            file,
+            flate2::Compression::new(level as u32),
+        )),
+        Comp::Zstd => {
+            let mut enc = zstd::Encoder::new(file, level)?;
+            if threads > 1 {
+                enc.multithread(threads)?;
+            }
+            Box::new(enc)
+        }
+        Comp::Xz => {
+            let stream = MtStreamBuilder::new()
+                .threads(threads)
+                .preset(level as u32)
+                .check(Check::Crc64)
+                .encoder()?;
+            Box::new(XzEncoder::new_stream(file, stream))
+        }
+        Comp::Lz4 => Box::new(FrameEncoder::new(file)),
+    };
+    Ok(enc)
+}
+
+fn create_decoder<R: Read + 'static>(comp: Comp, reader: R) -> Result<Box<dyn Read>> {
+    let dec: Box<dyn Read> = match comp {
+        Comp::None => Box::new(reader),
+        Comp::Bzip2 => Box::new(BzDecoder::new(reader)),
+        Comp::Gzip => Box::new(GzDecoder::new(reader)),
+        Comp::Zlib => Box::new(ZlibDecoder::new(reader)),
+        Comp::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Comp::Xz => Box::new(XzDecoder::new(reader)),
+        Comp::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+    };
+    Ok(dec)
+}
+
+fn create_header<P: AsRef<Path>>(path: P, size: u64, mtime: u64) -> Result<Header> {
     let mut header = Header::new_gnu();
     header.set_path(path)?;
     header.set_device_major(0)?;
@@ -102,11 +590,22 @@ fn create_header<P: AsRef<Path>>(path: P, size: u64) -> Result<Header> {
     header.set_gid(0);
     header.set_mode(0o644);
     header.set_entry_type(EntryType::file());
-    header.set_mtime(current_time());
+    header.set_mtime(mtime);
     header.set_cksum();
     Ok(header)
 }
 
+/// Name of this host, if it can be read
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
 fn current_time() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -114,39 +613,19 @@ fn current_time() -> u64 {
         .expect("System time before EPOCH!")
 }
 
-fn read_dir(dir: PathBuf, entries: &mut Vec<PathBuf>) -> Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                read_dir(path, entries)?;
-            } else {
-                entries.push(path);
-            }
-        }
-    }
-    Ok(())
-}
-
-fn resolve_paths(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
-    let mut entries: Vec<PathBuf> = vec![];
-    for path in paths {
-        if !path.exists() {
-            bail!(
-                "File '{}' does not exist!",
-                path.as_os_str().to_string_lossy()
-            );
-        }
-        if path.is_file() {
-            entries.push(path);
-            continue;
-        }
-        if path.is_dir() {
-            read_dir(path, &mut entries)?
-        }
+/// Time set by SOURCE_DATE_EPOCH environment variable, in seconds since Unix epoch
+fn source_date_epoch() -> Result<Option<u64>> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => match epoch.trim().parse() {
+            Ok(epoch) => Ok(Some(epoch)),
+            Err(_) => bail!(
+                "SOURCE_DATE_EPOCH must be a number of seconds, got '{}'",
+                epoch
+            ),
+        },
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => bail!("SOURCE_DATE_EPOCH must be a number of seconds"),
     }
-    Ok(entries)
 }
 
 fn sanitize_path(mut path: PathBuf, compression: Comp) -> PathBuf {
@@ -159,17 +638,101 @@ fn sanitize_path(mut path: PathBuf, compression: Comp) -> PathBuf {
     } else {
         path.push("out")
     }
-    path.with_extension(format!("tar.{}", compression))
+    match compression {
+        Comp::None => path.with_extension("tar"),
+        _ => path.with_extension(format!("tar.{}", compression)),
+    }
 }
 
-fn calculate_md5<P: AsRef<Path>>(file: P) -> Result<Digest> {
-    let mut file = File::open(file)?;
-    let mut ctx = md5::Context::new();
-    let mut buf = [0; 4194304];
-    let mut n = file.read(&mut buf[..])?;
-    while n != 0 {
-        ctx.consume(&buf[..n]);
-        n = file.read(&mut buf[..])?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    /// Names of all entries in the plain tar archive at `path`
+    fn entry_names(path: &Path) -> Vec<PathBuf> {
+        let mut archive = tar::Archive::new(File::open(path).unwrap());
+        let entries = archive.entries().unwrap();
+        entries
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect()
+    }
+
+    /// Writer of a full disk
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn errors_writing_last_blocks_are_reported() {
+        for comp in Comp::value_variants() {
+            let enc = create_encoder(*comp, comp.best_level(), 1, Box::new(Full)).unwrap();
+            let mut compressor = Compressor::spawn(enc);
+            compressor.write_all(b"data").unwrap();
+            assert!(compressor.finish().is_err(), "{}", comp.name());
+        }
+    }
+
+    #[test]
+    fn reproducible_archives_do_not_depend_on_jobs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("d")).unwrap();
+        for i in 0..20 {
+            fs::write(src.join(format!("f{}", i)), i.to_string().repeat(i * 1000)).unwrap();
+        }
+        fs::write(src.join("d/f"), "data").unwrap();
+        std::os::unix::fs::symlink("f1", src.join("l")).unwrap();
+
+        let archive = |jobs: &str| {
+            let output = tmp.path().join(format!("out-{}.tar.gz", jobs));
+            create(Cli::parse_from([
+                OsStr::new("archiver"),
+                OsStr::new("-i"),
+                src.as_os_str(),
+                OsStr::new("-o"),
+                output.as_os_str(),
+                OsStr::new("-c"),
+                OsStr::new("gzip"),
+                OsStr::new("--jobs"),
+                OsStr::new(jobs),
+                OsStr::new("--reproducible"),
+            ]))
+            .unwrap();
+            fs::read(output).unwrap()
+        };
+        assert!(archive("1") == archive("8"));
+    }
+
+    #[test]
+    fn existing_output_under_input_is_not_archived() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("src")).unwrap();
+        fs::write(tmp.path().join("src/data"), "data").unwrap();
+        let output = tmp.path().join("src/out.tar");
+        let args = [
+            OsStr::new("archiver"),
+            OsStr::new("-C"),
+            tmp.path().as_os_str(),
+            OsStr::new("-i"),
+            OsStr::new("src"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+            OsStr::new("-c"),
+            OsStr::new("none"),
+        ];
+        create(Cli::parse_from(args)).unwrap();
+        create(Cli::parse_from(args)).unwrap();
+        let names = entry_names(&output);
+        assert!(names.contains(&PathBuf::from("src/data")));
+        assert!(!names.contains(&PathBuf::from("src/out.tar")));
     }
-    Ok(ctx.compute())
 }
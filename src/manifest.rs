@@ -0,0 +1,382 @@
+//! Model of meta.json, the manifest appended to the end of every archive.
+//!
+//! Manifests are written in the latest schema version only. Older versions are migrated to it
+//! when they are read, so readers deal with a single model
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use schemars::{JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str;
+use tar::EntryType;
+
+use crate::archive::{normalize, Record};
+use crate::digest::{Checksums, HashAlg};
+use crate::pipeline::{Archived, Written};
+
+/// Version of the manifest layout written by this build. Manifests without `schema_version`
+/// are version 0
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Contents of meta.json
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "Archiver manifest")]
+pub struct Manifest {
+    /// Version of this layout, incremented on incompatible changes
+    pub schema_version: u32,
+    /// Version of archiver which created the archive
+    pub version: Option<String>,
+    /// Host the archive was created on, left out of reproducible archives
+    pub hostname: Option<String>,
+    /// Arguments archiver was run with, left out of reproducible archives
+    pub command_line: Option<Vec<String>>,
+    /// Creation time, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Compression algorithm
+    pub compression: Option<String>,
+    /// Compression level
+    pub level: Option<i32>,
+    /// Checksum algorithms computed for every file
+    pub algorithms: Vec<String>,
+    /// Number of regular files
+    pub total_files: u64,
+    /// Sum of sizes of regular files
+    pub total_bytes: u64,
+    /// Archived entries keyed by their path in the archive. Backslashes are escaped as `\\`,
+    /// and bytes which aren't valid UTF-8 as `\xNN`
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// Archived path. Its metadata is only missing in manifests migrated from schema version 0
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Entry {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    /// Base64 of the path, present when it isn't valid UTF-8 and its key is escaped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_bytes: Option<String>,
+    /// Size of file data, including holes of sparse files
+    pub size: Option<u64>,
+    /// Permission bits
+    pub mode: Option<u32>,
+    /// Modification time, in seconds since the Unix epoch
+    pub mtime: Option<u64>,
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Checksums of file data by algorithm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<Checksums>,
+    /// Target of a symlink or hard link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Base64 of the target, present when it isn't valid UTF-8 and `target` is escaped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_bytes: Option<String>,
+    /// Names of stored extended attributes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<String>,
+    /// Where data of an entry not read from disk came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    /// Shell command whose output was archived
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+/// Type of an archived entry
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+    Hardlink,
+    Fifo,
+    Char,
+    Block,
+    Other,
+}
+
+/// Stream an entry was archived from
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Stdin,
+    Command,
+}
+
+/// meta.json written before `schema_version` was introduced, holding md5 of every file
+#[derive(Deserialize)]
+struct ManifestV0 {
+    timestamp: u64,
+    checksums: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Parse meta.json of any schema version, migrating it to the current one
+    pub fn parse(data: &[u8]) -> Result<Manifest> {
+        let value: Value = serde_json::from_slice(data).context("Malformed meta.json")?;
+        let version = match value.get("schema_version") {
+            Some(version) => version
+                .as_u64()
+                .context("Malformed meta.json schema version")?,
+            None => 0,
+        };
+        match version {
+            0 => {
+                let manifest = serde_json::from_value(value).context("Malformed meta.json")?;
+                Ok(Manifest::migrate_v0(manifest))
+            }
+            1 => serde_json::from_value(value).context("Malformed meta.json"),
+            version => bail!("Unsupported meta.json schema version {}", version),
+        }
+    }
+
+    fn migrate_v0(old: ManifestV0) -> Manifest {
+        let mut entries = BTreeMap::new();
+        for (path, md5) in old.checksums {
+            let mut entry = Entry::unknown(Kind::File);
+            entry.checksums = Some(Checksums::from([(HashAlg::Md5.to_string(), md5)]));
+            // Paths were stored verbatim, so their backslashes need escaping
+            entries.insert(display_name(Path::new(&path)), entry);
+        }
+        Manifest {
+            schema_version: SCHEMA_VERSION,
+            version: None,
+            hostname: None,
+            command_line: None,
+            timestamp: old.timestamp,
+            compression: None,
+            level: None,
+            algorithms: vec![HashAlg::Md5.to_string()],
+            total_files: entries.len() as u64,
+            // Sizes were not recorded
+            total_bytes: 0,
+            entries,
+        }
+    }
+
+    /// Records of entries meta.json tracks, keyed by normalized path
+    pub fn records(&self) -> Result<HashMap<PathBuf, Record>> {
+        let mut records = HashMap::new();
+        for (path, entry) in &self.entries {
+            let target = || -> Result<PathBuf> {
+                let target = entry
+                    .target
+                    .as_deref()
+                    .with_context(|| format!("Link target of '{}' is missing", path))?;
+                decode_path(target, entry.target_bytes.as_deref())
+            };
+            let record = match entry.kind {
+                Kind::File => Record::File(
+                    entry
+                        .checksums
+                        .clone()
+                        .with_context(|| format!("Checksums of '{}' are missing", path))?,
+                ),
+                Kind::Symlink => Record::Symlink(target()?),
+                Kind::Hardlink => Record::HardLink(target()?),
+                _ => continue,
+            };
+            let path = decode_path(path, entry.path_bytes.as_deref())?;
+            records.insert(normalize(&path), record);
+        }
+        Ok(records)
+    }
+
+    /// JSON Schema of the current manifest layout
+    pub fn schema() -> Schema {
+        schemars::schema_for!(Manifest)
+    }
+}
+
+impl Entry {
+    /// Describe an entry written into the archive
+    pub fn new(written: &Written) -> Result<Entry> {
+        let header = &written.header;
+        let entry_type = header.entry_type();
+        let size = match header.as_gnu() {
+            Some(gnu) if entry_type.is_gnu_sparse() => gnu.real_size()?,
+            _ => header.size()?,
+        };
+        let mut entry = Entry {
+            kind: Kind::from(entry_type),
+            path_bytes: encode_bytes(&written.path),
+            size: Some(size),
+            mode: Some(header.mode()? & 0o7777),
+            mtime: Some(header.mtime()?),
+            uid: Some(header.uid()?),
+            gid: Some(header.gid()?),
+            user: written.user.clone(),
+            group: written.group.clone(),
+            checksums: None,
+            target: None,
+            target_bytes: None,
+            xattrs: written.xattrs.clone(),
+            source: None,
+            command: None,
+        };
+        match &written.archived {
+            Archived::File(checksums) => entry.checksums = Some(checksums.clone()),
+            Archived::Symlink(target) | Archived::HardLink(target) => {
+                entry.target = Some(display_name(target));
+                entry.target_bytes = encode_bytes(target);
+            }
+            Archived::Other => {}
+        }
+        Ok(entry)
+    }
+
+    /// Entry of a migrated manifest, which didn't record metadata
+    fn unknown(kind: Kind) -> Entry {
+        Entry {
+            kind,
+            path_bytes: None,
+            size: None,
+            mode: None,
+            mtime: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            checksums: None,
+            target: None,
+            target_bytes: None,
+            xattrs: vec![],
+            source: None,
+            command: None,
+        }
+    }
+}
+
+/// Text form of `path` used in meta.json. Backslashes are escaped as `\\` and bytes which aren't
+/// valid UTF-8 as `\xNN`, so different paths never share a name
+pub fn display_name(path: &Path) -> String {
+    let mut name = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        name.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            name.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    name
+}
+
+/// Base64 of `path`, if it isn't valid UTF-8 and can't be recovered from its display name
+pub fn encode_bytes(path: &Path) -> Option<String> {
+    let bytes = path.as_os_str().as_bytes();
+    str::from_utf8(bytes).is_err().then(|| BASE64.encode(bytes))
+}
+
+/// Path recorded as `name`, or as `bytes` if they are present
+fn decode_path(name: &str, bytes: Option<&str>) -> Result<PathBuf> {
+    match bytes {
+        Some(bytes) => decode_bytes(name, bytes),
+        None => parse_name(name),
+    }
+}
+
+fn decode_bytes(name: &str, bytes: &str) -> Result<PathBuf> {
+    let bytes = BASE64
+        .decode(bytes)
+        .with_context(|| format!("Malformed bytes of '{}'", name))?;
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Inverse of [`display_name`]
+fn parse_name(name: &str) -> Result<PathBuf> {
+    let malformed = || format!("Malformed escape sequence in '{}'", name);
+    let mut bytes = vec![];
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b'x', hi, lo, tail @ ..] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let hex = str::from_utf8(&rest[1..3]).expect("Hex digits are ASCII");
+                bytes.push(u8::from_str_radix(hex, 16).expect("Hex digits were checked"));
+                rest = tail;
+            }
+            _ => bail!(malformed()),
+        }
+    }
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+impl From<EntryType> for Kind {
+    fn from(entry_type: EntryType) -> Kind {
+        match entry_type {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => Kind::File,
+            EntryType::Directory => Kind::Directory,
+            EntryType::Symlink => Kind::Symlink,
+            EntryType::Link => Kind::Hardlink,
+            EntryType::Fifo => Kind::Fifo,
+            EntryType::Char => Kind::Char,
+            EntryType::Block => Kind::Block,
+            _ => Kind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn escaped_names_do_not_collide() {
+        let literal = Path::new(r"n\xff");
+        let raw = Path::new(OsStr::from_bytes(b"n\xff"));
+        assert_eq!(display_name(literal), r"n\\xff");
+        assert_eq!(display_name(raw), r"n\xff");
+        assert_eq!(parse_name(&display_name(literal)).unwrap(), literal);
+        assert_eq!(parse_name(&display_name(raw)).unwrap(), raw);
+    }
+
+    #[test]
+    fn malformed_escapes_are_refused() {
+        for name in [r"a\", r"a\x", r"a\xf", r"a\x+f", r"a\n"] {
+            assert!(parse_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn version_0_is_migrated() {
+        let meta =
+            br#"{"timestamp": 7, "checksums": {"a\\b": "764efa883dda1e11db47671c4a3bbd9e"}}"#;
+        let manifest = Manifest::parse(meta).unwrap();
+        assert_eq!(manifest.schema_version, SCHEMA_VERSION);
+        assert_eq!((manifest.timestamp, manifest.total_files), (7, 1));
+        assert!(manifest.entries[r"a\\b"].kind == Kind::File);
+        let records = manifest.records().unwrap();
+        assert!(matches!(
+            &records[Path::new(r"a\b")],
+            Record::File(checksums) if checksums["md5"] == "764efa883dda1e11db47671c4a3bbd9e"
+        ));
+    }
+
+    #[test]
+    fn published_schema_is_up_to_date() {
+        let published: Value = serde_json::from_str(include_str!("../meta.schema.json")).unwrap();
+        assert!(
+            serde_json::to_value(Manifest::schema()).unwrap() == published,
+            "meta.schema.json is outdated, regenerate it with `archiver schema > meta.schema.json`"
+        );
+    }
+}
@@ -0,0 +1,422 @@
+//! Owners and permissions of archived entries, as rewritten by `--owner`, `--group`,
+//! `--uid-map`, `--gid-map` and `--mode`
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::{mem, ptr};
+use tar::Header;
+
+/// User or group as stored in the archive
+#[derive(Clone)]
+pub struct Id {
+    pub id: u64,
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    User,
+    Group,
+}
+
+/// Key of `--uid-map` and `--gid-map` entries
+#[derive(PartialEq, Eq, Hash)]
+pub enum Source {
+    Id(u64),
+    Name(String),
+}
+
+/// Rewrites owners and permissions of entry headers
+#[derive(Default)]
+pub struct Ownership {
+    /// Owner of every entry
+    owner: Option<Id>,
+    /// Group of every entry
+    group: Option<Id>,
+    uid_map: HashMap<Source, Id>,
+    gid_map: HashMap<Source, Id>,
+    /// Don't store user and group names
+    numeric: bool,
+    mode: Option<Mode>,
+    /// Names of users and groups looked up so far
+    users: Mutex<HashMap<u64, Option<String>>>,
+    groups: Mutex<HashMap<u64, Option<String>>>,
+}
+
+impl Ownership {
+    pub fn new(
+        owner: Option<Id>,
+        group: Option<Id>,
+        uid_map: HashMap<Source, Id>,
+        gid_map: HashMap<Source, Id>,
+        numeric: bool,
+        mode: Option<Mode>,
+    ) -> Self {
+        Ownership {
+            owner,
+            group,
+            uid_map,
+            gid_map,
+            numeric,
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Rewrite owner, group and permissions in `header` of an entry, returning user and group names
+    pub fn apply(
+        &self,
+        header: &mut Header,
+        is_dir: bool,
+    ) -> io::Result<(Option<String>, Option<String>)> {
+        let user = self.map(Kind::User, header.uid()?);
+        let group = self.map(Kind::Group, header.gid()?);
+        header.set_uid(user.id);
+        header.set_gid(group.id);
+        // Names longer than header fields are still stored in PAX records
+        let _ = header.set_username(user.name.as_deref().unwrap_or(""));
+        let _ = header.set_groupname(group.name.as_deref().unwrap_or(""));
+        if let Some(mode) = &self.mode {
+            header.set_mode(mode.apply(header.mode()?, is_dir));
+        }
+        Ok((user.name, group.name))
+    }
+
+    fn map(&self, kind: Kind, id: u64) -> Id {
+        let (forced, map, cache) = match kind {
+            Kind::User => (&self.owner, &self.uid_map, &self.users),
+            Kind::Group => (&self.group, &self.gid_map, &self.groups),
+        };
+        let mut mapped = forced.clone().unwrap_or_else(|| {
+            let name = cache
+                .lock()
+                .expect("Name lookup panicked")
+                .entry(id)
+                .or_insert_with(|| name_of(kind, id))
+                .clone();
+            let by_name = name.clone().map(Source::Name);
+            map.get(&Source::Id(id))
+                .or_else(|| by_name.and_then(|name| map.get(&name)))
+                .cloned()
+                .unwrap_or(Id { id, name })
+        });
+        if self.numeric {
+            mapped.name = None;
+        }
+        mapped
+    }
+}
+
+/// Permission changes given by `--mode`, in chmod syntax
+#[derive(Clone)]
+pub struct Mode(Vec<Clause>);
+
+#[derive(Clone)]
+struct Clause {
+    /// Permission bits the clause applies to
+    who: u32,
+    op: char,
+    perms: Perms,
+}
+
+#[derive(Clone)]
+enum Perms {
+    Octal(u32),
+    /// Letters of `rwxXst`
+    Symbolic(String),
+}
+
+impl Mode {
+    /// Parse octal mode or comma-separated symbolic clauses, e.g. `go-w,a+rX`
+    pub fn parse(arg: &str) -> Result<Mode, String> {
+        // `from_str_radix` also accepts a sign, which would read e.g. `+7` as `=0007`
+        let is_octal = !arg.is_empty() && arg.bytes().all(|b| matches!(b, b'0'..=b'7'));
+        if is_octal {
+            let mode = u32::from_str_radix(arg, 8).map_err(|err| err.to_string())?;
+            if mode > 0o7777 {
+                return Err("octal mode must be at most 7777".to_owned());
+            }
+            return Ok(Mode(vec![Clause {
+                who: 0o7777,
+                op: '=',
+                perms: Perms::Octal(mode),
+            }]));
+        }
+        let mut clauses = vec![];
+        for clause in arg.split(',') {
+            let ops = clause.trim_start_matches(['u', 'g', 'o', 'a']);
+            let mut who = 0;
+            for c in clause[..clause.len() - ops.len()].chars() {
+                who |= match c {
+                    'u' => 0o4700,
+                    'g' => 0o2070,
+                    'o' => 0o1007,
+                    _ => 0o7777,
+                };
+            }
+            if who == 0 {
+                who = 0o7777;
+            }
+            // Every operator starts a new clause for the same users, e.g. `u+x-w`
+            let mut rest = ops;
+            if rest.is_empty() {
+                return Err(format!("missing operator in '{}'", clause));
+            }
+            while let Some(op) = rest.chars().next().filter(|c| "+-=".contains(*c)) {
+                let perms_len = rest[1..].find(['+', '-', '=']).unwrap_or(rest.len() - 1);
+                let perms = &rest[1..1 + perms_len];
+                if let Some(c) = perms.chars().find(|c| !"rwxXst".contains(*c)) {
+                    return Err(format!("invalid permission '{}' in '{}'", c, clause));
+                }
+                clauses.push(Clause {
+                    who,
+                    op,
+                    perms: Perms::Symbolic(perms.to_owned()),
+                });
+                rest = &rest[1 + perms_len..];
+            }
+            if !rest.is_empty() {
+                return Err(format!("invalid mode '{}'", clause));
+            }
+        }
+        Ok(Mode(clauses))
+    }
+
+    /// Apply changes to permissions `mode` of an entry
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for clause in &self.0 {
+            let bits = match &clause.perms {
+                Perms::Octal(bits) => *bits,
+                Perms::Symbolic(perms) => perms.chars().fold(0, |bits, c| {
+                    bits | match c {
+                        'r' => 0o444,
+                        'w' => 0o222,
+                        'x' => 0o111,
+                        // Executable bit only for directories and files executable by someone
+                        'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                        's' => 0o6000,
+                        't' => 0o1000,
+                        _ => 0,
+                    }
+                }),
+            } & clause.who;
+            mode = match clause.op {
+                '+' => mode | bits,
+                '-' => mode & !bits,
+                _ => mode & !clause.who | bits,
+            };
+        }
+        mode
+    }
+}
+
+/// Parse `--owner` or `--group` argument: `NAME`, `NAME:ID` or numeric `ID`
+pub fn parse_user(arg: &str) -> Result<Id> {
+    parse_id(Kind::User, arg)
+}
+
+pub fn parse_group(arg: &str) -> Result<Id> {
+    parse_id(Kind::Group, arg)
+}
+
+fn parse_id(kind: Kind, arg: &str) -> Result<Id> {
+    let arg = arg.strip_prefix('+').unwrap_or(arg);
+    if let Ok(id) = arg.parse() {
+        return Ok(Id {
+            id,
+            name: name_of(kind, id),
+        });
+    }
+    let (name, id) = match arg.split_once(':') {
+        Some((name, id)) => (
+            name,
+            id.parse().with_context(|| format!("Invalid id '{}'", id))?,
+        ),
+        None => match id_of(kind, arg) {
+            Some(id) => (arg, id),
+            None => bail!("Unknown {} '{}'", kind.name(), arg),
+        },
+    };
+    if name.is_empty() {
+        bail!("Empty {} name", kind.name());
+    }
+    Ok(Id {
+        id,
+        name: Some(name.to_owned()),
+    })
+}
+
+/// Read `--uid-map` or `--gid-map` file.
+///
+/// Each line maps a user or group given by name or `+ID` to `NAME`, `NAME:ID` or `+ID`,
+/// like `--owner-map` and `--group-map` of GNU tar. Empty lines and lines starting with `#`
+/// are ignored
+pub fn read_uid_map(path: &Path) -> Result<HashMap<Source, Id>> {
+    read_map(Kind::User, path)
+}
+
+pub fn read_gid_map(path: &Path) -> Result<HashMap<Source, Id>> {
+    read_map(Kind::Group, path)
+}
+
+fn read_map(kind: Kind, path: &Path) -> Result<HashMap<Source, Id>> {
+    let display = path.as_os_str().to_string_lossy();
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {} map '{}'", kind.name(), display))?;
+    let mut map = HashMap::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("Invalid line {} of '{}'", n + 1, display);
+        let mut fields = line.split_whitespace();
+        let (Some(source), Some(target), None) = (fields.next(), fields.next(), fields.next())
+        else {
+            bail!("{}: expected source and target", context());
+        };
+        let source = match source.strip_prefix('+') {
+            Some(id) => Source::Id(id.parse().with_context(context)?),
+            None => Source::Name(source.to_owned()),
+        };
+        map.insert(source, parse_id(kind, target).with_context(context)?);
+    }
+    Ok(map)
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::User => "user",
+            Kind::Group => "group",
+        }
+    }
+}
+
+/// Name of the user or group with `id` on this host
+fn name_of(kind: Kind, id: u64) -> Option<String> {
+    let id = u32::try_from(id).ok()?;
+    // Name points into the buffer, so it is copied before the buffer is reused
+    let name = |name: *const libc::c_char| {
+        // SAFETY: names of found entries are NUL-terminated strings
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    lookup(|buf| match kind {
+        Kind::User => {
+            // SAFETY: all pointers are valid for the duration of the call, and the buffer
+            // length is passed along with it
+            let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut found = ptr::null_mut();
+            let err =
+                unsafe { libc::getpwuid_r(id, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+            (err, (!found.is_null()).then(|| name(pwd.pw_name)))
+        }
+        Kind::Group => {
+            // SAFETY: same as above
+            let mut grp: libc::group = unsafe { mem::zeroed() };
+            let mut found = ptr::null_mut();
+            let err =
+                unsafe { libc::getgrgid_r(id, &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) };
+            (err, (!found.is_null()).then(|| name(grp.gr_name)))
+        }
+    })
+}
+
+/// Id of the user or group with `name` on this host
+fn id_of(kind: Kind, name: &str) -> Option<u64> {
+    let name = CString::new(name).ok()?;
+    lookup(|buf| match kind {
+        Kind::User => {
+            // SAFETY: all pointers are valid for the duration of the call, and the buffer
+            // length is passed along with it
+            let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut found = ptr::null_mut();
+            let err = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            };
+            (err, (!found.is_null()).then_some(u64::from(pwd.pw_uid)))
+        }
+        Kind::Group => {
+            // SAFETY: same as above
+            let mut grp: libc::group = unsafe { mem::zeroed() };
+            let mut found = ptr::null_mut();
+            let err = unsafe {
+                libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut grp,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            };
+            (err, (!found.is_null()).then_some(u64::from(grp.gr_gid)))
+        }
+    })
+}
+
+/// Run reentrant lookup in the user or group database, growing its buffer until the entry fits
+fn lookup<T>(mut call: impl FnMut(&mut [libc::c_char]) -> (libc::c_int, Option<T>)) -> Option<T> {
+    let mut buf = vec![0; 1024];
+    loop {
+        match call(&mut buf) {
+            (libc::ERANGE, _) if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            (0, found) => return found,
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(mode: &str, old: u32, is_dir: bool) -> u32 {
+        Mode::parse(mode).unwrap().apply(old, is_dir)
+    }
+
+    #[test]
+    fn octal_mode_replaces_permissions() {
+        assert_eq!(apply("644", 0o4755, false), 0o644);
+        assert_eq!(apply("0750", 0o600, true), 0o750);
+        assert_eq!(apply("7777", 0, false), 0o7777);
+    }
+
+    #[test]
+    fn symbolic_mode_changes_permissions() {
+        assert_eq!(apply("go-w", 0o777, false), 0o755);
+        assert_eq!(apply("u+x,g=r,o=", 0o666, false), 0o740);
+        assert_eq!(apply("a+r", 0o600, false), 0o644);
+        assert_eq!(apply("+x", 0o644, false), 0o755);
+        assert_eq!(apply("u+x-w", 0o644, false), 0o544);
+        assert_eq!(apply("u+s,+t", 0o755, true), 0o5755);
+    }
+
+    #[test]
+    fn capital_x_applies_to_directories_and_executables() {
+        assert_eq!(apply("a+rX", 0o600, false), 0o644);
+        assert_eq!(apply("a+rX", 0o700, false), 0o755);
+        assert_eq!(apply("a+rX", 0o700, true), 0o755);
+        assert_eq!(apply("a+rX", 0o600, true), 0o755);
+    }
+
+    #[test]
+    fn invalid_modes_are_refused() {
+        for mode in [
+            "", "+7", "-7", "=644", "8", "17777", "q+x", "u", "u+z", "u+x,", "a+r x",
+        ] {
+            assert!(Mode::parse(mode).is_err(), "{}", mode);
+        }
+    }
+}
@@ -0,0 +1,509 @@
+//! Archiving pipeline.
+//!
+//! Directory traversal, reading and hashing of files and compression run on separate threads
+//! connected with bounded queues. Files are written into the archive in traversal order,
+//! no matter which reader finishes first.
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use tar::{EntryType, Header, HeaderMode};
+
+use crate::digest::{Checksums, Digests, HashAlg, HashingReader};
+use crate::owner::Ownership;
+use crate::sparse::{self, Region};
+use crate::walk::{self, resolve_paths, Found, Input, WalkOptions};
+use crate::xattrs::{self, XattrOptions, PAX_PREFIX};
+
+const CHUNK_SIZE: usize = 1 << 20;
+/// Number of chunks a reader may get ahead of the archive writer, per file
+const CHUNKS_PER_FILE: usize = 4;
+
+pub struct Options {
+    /// Checksum algorithms to compute for every file
+    pub algs: Vec<HashAlg>,
+    /// Number of threads reading and hashing files
+    pub jobs: usize,
+    pub walk: WalkOptions,
+    pub xattrs: XattrOptions,
+    /// Normalize owners and permissions, and don't depend on how files are laid out on disk
+    pub reproducible: bool,
+    /// Mtimes later than this are set to it
+    pub clamp_mtime: Option<u64>,
+    pub ownership: Ownership,
+}
+
+/// Path written into the archive
+pub struct Written {
+    pub path: PathBuf,
+    pub archived: Archived,
+    pub header: Box<Header>,
+    /// Names of extended attributes stored along with the entry
+    pub xattrs: Vec<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+/// What was written into the archive for a path
+pub enum Archived {
+    File(Checksums),
+    Symlink(PathBuf),
+    HardLink(PathBuf),
+    /// Directory or special file, these have nothing to record in meta.json
+    Other,
+}
+
+/// Message from a file reader to the archive writer
+enum Chunk {
+    /// PAX records of the entry, sent before its header
+    Pax(Vec<(String, Vec<u8>)>),
+    Header(Box<Header>),
+    /// Header of a symlink or hard link, pointing to the target path
+    Link(Box<Header>, PathBuf),
+    /// Header of an entry without data
+    Empty(Box<Header>),
+    Data(Vec<u8>),
+    Done(Checksums),
+    Failed(io::Error),
+}
+
+/// Archive everything under `inputs`.
+///
+/// Returns archived paths in the order they were written
+pub fn run<W: Write>(
+    tar: &mut tar::Builder<W>,
+    inputs: Vec<Input>,
+    options: &Options,
+) -> Result<Vec<Written>> {
+    let jobs = options.jobs;
+    let (jobs_tx, jobs_rx) = sync_channel::<(Found, SyncSender<Chunk>)>(jobs);
+    let jobs_rx = Mutex::new(jobs_rx);
+
+    thread::scope(|s| {
+        let (files_tx, files_rx) = sync_channel(jobs * 2);
+        let walker = s.spawn(move || {
+            resolve_paths(inputs, &options.walk, &mut |found| {
+                let (tx, rx) = sync_channel(CHUNKS_PER_FILE);
+                // Receivers are dropped only when writing has failed, that error is reported instead
+                if files_tx.send((found.name.clone(), rx)).is_err()
+                    || jobs_tx.send((found, tx)).is_err()
+                {
+                    bail!("Archiving aborted");
+                }
+                Ok(())
+            })
+        });
+        for _ in 0..jobs {
+            s.spawn(|| read_files(&jobs_rx, options));
+        }
+
+        let written = write_files(tar, files_rx);
+        let walked = walker.join().expect("Traversal thread panicked");
+        let written = written?;
+        walked?;
+        Ok(written)
+    })
+}
+
+fn read_files(jobs: &Mutex<Receiver<(Found, SyncSender<Chunk>)>>, options: &Options) {
+    loop {
+        let job = jobs.lock().expect("Reader thread panicked").recv();
+        let Ok((found, tx)) = job else {
+            return;
+        };
+        if let Err(err) = read_file(&found, options, &tx) {
+            let _ = tx.send(Chunk::Failed(err));
+        }
+    }
+}
+
+/// Send header and contents of the file to the writer, hashing exactly the bytes being sent
+fn read_file(found: &Found, options: &Options, tx: &SyncSender<Chunk>) -> io::Result<()> {
+    let path = &found.path;
+    let meta = walk::metadata(path, options.walk.follow_symlinks)?;
+    let file_type = meta.file_type();
+    let mut header = Header::new_gnu();
+    if options.reproducible {
+        header.set_metadata_in_mode(&meta, HeaderMode::Deterministic);
+        header.set_mtime(meta.mtime().max(0) as u64);
+    } else {
+        header.set_metadata(&meta);
+    }
+    if let Some(clamp_mtime) = options.clamp_mtime {
+        header.set_mtime(header.mtime()?.min(clamp_mtime));
+    }
+    let (user, group) = options.ownership.apply(&mut header, file_type.is_dir())?;
+
+    let mut pax = vec![];
+    // Hard links share attributes with their target, which is archived first
+    if found.hard_link.is_none() {
+        let xattrs = xattrs::read(path, options.walk.follow_symlinks, options.xattrs)?;
+        pax.extend(
+            xattrs
+                .into_iter()
+                .map(|(name, value)| (format!("{}{}", PAX_PREFIX, name), value)),
+        );
+    }
+    pax.extend(user.map(|user| ("uname".to_owned(), user.into_bytes())));
+    pax.extend(group.map(|group| ("gname".to_owned(), group.into_bytes())));
+    // Send errors mean that writer has failed, so there is nobody to report to
+    if !pax.is_empty() && tx.send(Chunk::Pax(pax)).is_err() {
+        return Ok(());
+    }
+    if let Some(target) = &found.hard_link {
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        let _ = tx.send(Chunk::Link(Box::new(header), target.clone()));
+        return Ok(());
+    }
+    if file_type.is_symlink() {
+        let _ = tx.send(Chunk::Link(Box::new(header), fs::read_link(path)?));
+        return Ok(());
+    }
+    if !file_type.is_file() {
+        if file_type.is_char_device() || file_type.is_block_device() {
+            header.set_device_major(libc::major(meta.rdev()))?;
+            header.set_device_minor(libc::minor(meta.rdev()))?;
+        }
+        header.set_size(0);
+        let _ = tx.send(Chunk::Empty(Box::new(header)));
+        return Ok(());
+    }
+
+    let file = File::open(path)?;
+    // Holes depend on how the file was written, not only on its contents
+    let regions = match options.reproducible {
+        true => None,
+        false => sparse::regions(&file, &meta)?,
+    };
+    let extended = match &regions {
+        Some(regions) => sparse::prepare_header(&mut header, meta.len(), regions),
+        None => vec![],
+    };
+    if tx.send(Chunk::Header(Box::new(header))).is_err() {
+        return Ok(());
+    }
+    if let Some(regions) = regions {
+        return read_sparse(file, &regions, extended, options, tx);
+    }
+
+    // File may grow while it is being archived, anything past the size in header is ignored
+    let mut reader = HashingReader::new(file.take(meta.len()), Digests::new(&options.algs));
+    loop {
+        let chunk = read_chunk(&mut reader)?;
+        if chunk.is_empty() {
+            break;
+        }
+        if tx.send(Chunk::Data(chunk)).is_err() {
+            return Ok(());
+        }
+    }
+    if reader.len() != meta.len() {
+        return Err(truncated());
+    }
+    let _ = tx.send(Chunk::Done(reader.finish()));
+    Ok(())
+}
+
+/// Send data regions of a sparse file, hashing its logical contents with holes read as zeros
+fn read_sparse(
+    mut file: File,
+    regions: &[Region],
+    extended: Vec<u8>,
+    options: &Options,
+    tx: &SyncSender<Chunk>,
+) -> io::Result<()> {
+    // Extended sparse headers are written right after the entry header, before data
+    if !extended.is_empty() && tx.send(Chunk::Data(extended)).is_err() {
+        return Ok(());
+    }
+    let mut digests = Digests::new(&options.algs);
+    let zeros = vec![0; CHUNK_SIZE];
+    let mut pos = 0;
+    for region in regions {
+        while pos < region.offset {
+            let len = (region.offset - pos).min(CHUNK_SIZE as u64);
+            digests.update(&zeros[..len as usize]);
+            pos += len;
+        }
+        file.seek(SeekFrom::Start(region.offset))?;
+        let mut data = (&mut file).take(region.len);
+        loop {
+            let chunk = read_chunk(&mut data)?;
+            if chunk.is_empty() {
+                break;
+            }
+            digests.update(&chunk);
+            pos += chunk.len() as u64;
+            if tx.send(Chunk::Data(chunk)).is_err() {
+                return Ok(());
+            }
+        }
+        if pos != region.offset + region.len {
+            return Err(truncated());
+        }
+    }
+    let _ = tx.send(Chunk::Done(digests.finish()));
+    Ok(())
+}
+
+/// Read up to `CHUNK_SIZE` bytes, nothing at the end of `reader`
+fn read_chunk<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    reader.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "file was truncated while being archived",
+    )
+}
+
+fn write_files<W: Write>(
+    tar: &mut tar::Builder<W>,
+    files: Receiver<(PathBuf, Receiver<Chunk>)>,
+) -> Result<Vec<Written>> {
+    let mut written = vec![];
+    for (path, chunks) in files {
+        let context = format!("Unable to archive '{}'", path.as_os_str().to_string_lossy());
+        written.push(append_file(tar, path, &chunks).context(context)?);
+    }
+    Ok(written)
+}
+
+fn append_file<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: PathBuf,
+    chunks: &Receiver<Chunk>,
+) -> Result<Written> {
+    let mut chunk = chunks.recv()?;
+    let mut pax = vec![];
+    if let Chunk::Pax(records) = chunk {
+        tar.append_pax_extensions(
+            records
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice())),
+        )?;
+        pax = records;
+        chunk = chunks.recv()?;
+    }
+    let (header, archived) = match chunk {
+        Chunk::Header(mut header) => {
+            let mut reader = ChunkReader {
+                chunks,
+                data: vec![],
+                pos: 0,
+                checksums: None,
+            };
+            tar.append_data(&mut header, &path, &mut reader)?;
+            let checksums = reader
+                .checksums
+                .context("File reader exited unexpectedly")?;
+            (header, Archived::File(checksums))
+        }
+        Chunk::Link(mut header, target) => {
+            tar.append_link(&mut header, &path, &target)?;
+            let archived = match header.entry_type() {
+                EntryType::Link => Archived::HardLink(target),
+                _ => Archived::Symlink(target),
+            };
+            (header, archived)
+        }
+        Chunk::Empty(mut header) => {
+            tar.append_data(&mut header, &path, io::empty())?;
+            (header, Archived::Other)
+        }
+        Chunk::Failed(err) => return Err(err.into()),
+        Chunk::Pax(_) | Chunk::Data(_) | Chunk::Done(_) => {
+            bail!("File data received before header")
+        }
+    };
+    let record = |key: &str| {
+        pax.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+    };
+    Ok(Written {
+        user: record("uname"),
+        group: record("gname"),
+        xattrs: pax
+            .iter()
+            .filter_map(|(key, _)| key.strip_prefix(PAX_PREFIX))
+            .map(str::to_owned)
+            .collect(),
+        path,
+        archived,
+        header,
+    })
+}
+
+/// Reads file contents sent by a reader thread
+struct ChunkReader<'a> {
+    chunks: &'a Receiver<Chunk>,
+    data: Vec<u8>,
+    pos: usize,
+    checksums: Option<Checksums>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() {
+            match self.chunks.recv() {
+                Ok(Chunk::Data(data)) => {
+                    self.data = data;
+                    self.pos = 0;
+                }
+                Ok(Chunk::Done(checksums)) => {
+                    self.checksums = Some(checksums);
+                    return Ok(0);
+                }
+                Ok(Chunk::Failed(err)) => return Err(err),
+                Ok(Chunk::Pax(_) | Chunk::Header(_) | Chunk::Link(..) | Chunk::Empty(_))
+                | Err(_) => return Err(io::Error::other("File reader exited unexpectedly")),
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Compressing writer, finished explicitly so that errors writing its last blocks are not lost
+pub trait Encoder: Write + Send {
+    /// Write remaining compressed data and flush the underlying writer
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Writer handing data over to a separate compression thread
+pub struct Compressor {
+    tx: Option<SyncSender<Vec<u8>>>,
+    buf: Vec<u8>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Compressor {
+    pub fn spawn(mut enc: Box<dyn Encoder>) -> Self {
+        let (tx, rx) = sync_channel::<Vec<u8>>(CHUNKS_PER_FILE);
+        let handle = thread::spawn(move || {
+            for data in rx {
+                enc.write_all(&data)?;
+            }
+            enc.finish()
+        });
+        Compressor {
+            tx: Some(tx),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            handle: Some(handle),
+        }
+    }
+
+    /// Compress remaining data and wait for the compression thread to exit
+    pub fn finish(mut self) -> Result<()> {
+        self.send()?;
+        drop(self.tx.take());
+        self.join()?;
+        Ok(())
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let sent = self.tx.as_ref().map(|tx| tx.send(data));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            // Compression thread exits early only on error
+            _ => Err(self.join().err().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "Compression thread exited")
+            })),
+        }
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        match self.handle.take() {
+            Some(handle) => handle.join().expect("Compression thread panicked"),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Compression thread exited",
+            )),
+        }
+    }
+}
+
+impl Write for Compressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+    use std::os::unix::fs::FileExt;
+
+    fn options(jobs: usize) -> Options {
+        Options {
+            algs: vec![HashAlg::Md5],
+            jobs,
+            walk: WalkOptions::default(),
+            xattrs: XattrOptions::default(),
+            reproducible: false,
+            clamp_mtime: None,
+            ownership: Ownership::default(),
+        }
+    }
+
+    #[test]
+    fn sparse_file_is_stored_as_data_regions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("disk.img");
+        let file = File::create(&path).unwrap();
+        file.set_len(16 << 20).unwrap();
+        // More regions than fit into the entry header
+        for i in 0..6u8 {
+            file.write_all_at(&[i + 1; 100], (i as u64 * 2 + 1) << 20)
+                .unwrap();
+        }
+        let contents = fs::read(&path).unwrap();
+
+        let mut tar = tar::Builder::new(vec![]);
+        let inputs = vec![Input {
+            path: path.clone(),
+            name: PathBuf::from("disk.img"),
+        }];
+        let options = options(1);
+        let written = run(&mut tar, inputs, &options).unwrap();
+        let Archived::File(checksums) = &written[0].archived else {
+            panic!("Sparse file is not archived as a file");
+        };
+        let expected = digest::calculate(Digests::new(&options.algs), contents.as_slice());
+        assert_eq!(checksums, &expected.unwrap());
+
+        let archive = tar.into_inner().unwrap();
+        assert!(archive.len() < 1 << 20);
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert!(entry.header().entry_type().is_gnu_sparse());
+        let mut unpacked = vec![];
+        entry.read_to_end(&mut unpacked).unwrap();
+        assert!(unpacked == contents);
+    }
+}
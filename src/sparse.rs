@@ -0,0 +1,217 @@
+//! Sparse files, stored in archives as old GNU sparse entries.
+//!
+//! Only data regions are read and stored, holes are recorded as offsets in the entry header
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use tar::{EntryType, GnuExtSparseHeader, Header};
+
+const BLOCK_SIZE: u64 = 512;
+/// Number of regions fitting into the entry header itself
+const HEADER_REGIONS: usize = 4;
+
+/// Region of a sparse file containing data
+pub struct Region {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Find data regions of a sparse file, `None` if the file has no holes or holes can't be detected.
+///
+/// Regions are aligned to tar blocks, as GNU sparse entries require
+pub fn regions(file: &File, meta: &Metadata) -> io::Result<Option<Vec<Region>>> {
+    let size = meta.len();
+    // Files with no holes have at least as many blocks allocated as their size needs
+    if size == 0 || meta.blocks() * BLOCK_SIZE >= size {
+        return Ok(None);
+    }
+
+    let mut regions: Vec<Region> = vec![];
+    let mut pos = 0;
+    while pos < size {
+        let Some(start) = seek(file, pos, libc::SEEK_DATA)? else {
+            break;
+        };
+        // Data up to the end of file is followed by an implicit hole
+        let end = seek(file, start, libc::SEEK_HOLE)?
+            .unwrap_or(size)
+            .min(size);
+        if end <= start {
+            break;
+        }
+        pos = add_region(&mut regions, start, end, size);
+    }
+    if regions.len() == 1 && regions[0].offset == 0 && regions[0].len == size {
+        return Ok(None);
+    }
+    // Empty region at the end records size of the file, which may end with a hole
+    if regions
+        .last()
+        .is_none_or(|last| last.offset + last.len < size)
+    {
+        regions.push(Region {
+            offset: size,
+            len: 0,
+        });
+    }
+    Ok(Some(regions))
+}
+
+/// Add data between `start` and `end` to `regions`, widened to whole tar blocks and merged with
+/// the previous region if they touch. Returns the end of the added region
+fn add_region(regions: &mut Vec<Region>, start: u64, end: u64, size: u64) -> u64 {
+    let start = start / BLOCK_SIZE * BLOCK_SIZE;
+    let end = end
+        .div_ceil(BLOCK_SIZE)
+        .saturating_mul(BLOCK_SIZE)
+        .min(size);
+    match regions.last_mut() {
+        Some(last) if last.offset + last.len >= start => last.len = end - last.offset,
+        _ => regions.push(Region {
+            offset: start,
+            len: end - start,
+        }),
+    }
+    end
+}
+
+/// `lseek` to the next data or hole, `None` if there is none past `offset`
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    // SAFETY: lseek doesn't access memory, file descriptor is valid while `file` is borrowed
+    let pos = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if pos < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(pos as u64))
+}
+
+/// Turn `header` of a regular file into a sparse entry header, storing only `regions`.
+///
+/// Returns extended headers holding regions which don't fit into the entry header, these must
+/// be written right after it, before file data
+pub fn prepare_header(header: &mut Header, size: u64, regions: &[Region]) -> Vec<u8> {
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(regions.iter().map(|r| r.len).sum());
+    let gnu = header
+        .as_gnu_mut()
+        .expect("Sparse entries require GNU header");
+    gnu.set_real_size(size);
+    for (region, sparse) in regions.iter().zip(&mut gnu.sparse) {
+        sparse.set_offset(region.offset);
+        sparse.set_length(region.len);
+    }
+    gnu.set_is_extended(regions.len() > HEADER_REGIONS);
+
+    let mut extended = vec![];
+    let mut rest = regions.iter().skip(HEADER_REGIONS).peekable();
+    while rest.peek().is_some() {
+        let mut ext = GnuExtSparseHeader::new();
+        for (sparse, region) in ext.sparse.iter_mut().zip(rest.by_ref()) {
+            sparse.set_offset(region.offset);
+            sparse.set_length(region.len);
+        }
+        ext.set_is_extended(rest.peek().is_some());
+        extended.extend_from_slice(ext.as_bytes());
+    }
+    extended
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use tar::{Archive, Builder};
+
+    const HOLE: u64 = 1 << 20;
+
+    fn pairs(regions: &[Region]) -> Vec<(u64, u64)> {
+        regions.iter().map(|r| (r.offset, r.len)).collect()
+    }
+
+    /// Sparse file of `size` bytes with `data` written at each of `offsets`
+    fn sparse_file(offsets: &[u64], data: &[u8], size: u64) -> File {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(size).unwrap();
+        for &offset in offsets {
+            file.write_all_at(data, offset).unwrap();
+        }
+        file
+    }
+
+    fn data_regions(file: &File) -> Vec<Region> {
+        regions(file, &file.metadata().unwrap()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn regions_are_aligned_to_blocks_and_merged() {
+        let mut regions = vec![];
+        assert_eq!(add_region(&mut regions, 100, 600, 10000), 1024);
+        // Touches the previous region once aligned
+        add_region(&mut regions, 1030, 1100, 10000);
+        add_region(&mut regions, 4000, 4100, 10000);
+        // Last block is cut at the end of file
+        add_region(&mut regions, 9990, 10000, 10000);
+        assert_eq!(pairs(&regions), [(0, 1536), (3584, 1024), (9728, 272)]);
+    }
+
+    #[test]
+    fn trailing_hole_is_recorded_as_empty_region() {
+        let file = sparse_file(&[0, 2 * HOLE], b"data", 4 * HOLE);
+        let regions = data_regions(&file);
+        let last = regions.last().unwrap();
+        assert_eq!((last.offset, last.len), (4 * HOLE, 0));
+        for region in &regions {
+            assert!(region.offset % BLOCK_SIZE == 0 && region.len % BLOCK_SIZE == 0);
+        }
+        assert_eq!((regions[0].offset, regions[1].offset), (0, 2 * HOLE));
+
+        // Data at the end of file needs no empty region
+        let file = sparse_file(&[2 * HOLE], b"data", 2 * HOLE + 4);
+        let regions = data_regions(&file);
+        let last = regions.last().unwrap();
+        assert_eq!(last.offset + last.len, 2 * HOLE + 4);
+    }
+
+    #[test]
+    fn regions_past_header_go_into_extended_headers() {
+        let size = 6 * 1024;
+        // Six data regions followed by a hole, as found by `regions`
+        let regions = (0..6)
+            .map(|i| Region {
+                offset: i * 1024,
+                len: 512,
+            })
+            .chain([Region {
+                offset: size,
+                len: 0,
+            }])
+            .collect::<Vec<_>>();
+        let mut header = Header::new_gnu();
+        header.set_path("sparse").unwrap();
+        header.set_mode(0o644);
+        let extended = prepare_header(&mut header, size, &regions);
+        assert_eq!(extended.len(), 512);
+        header.set_cksum();
+
+        let data = (0..6u8).flat_map(|i| [i + 1; 512]).collect::<Vec<_>>();
+        let mut tar = Builder::new(vec![]);
+        tar.append(&header, [extended, data].concat().as_slice())
+            .unwrap();
+        let archive = tar.into_inner().unwrap();
+
+        let mut archive = Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut contents = vec![];
+        io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+        let expected = (0..6u8)
+            .flat_map(|i| [[i + 1; 512], [0; 512]])
+            .flatten()
+            .collect::<Vec<_>>();
+        assert!(contents == expected);
+    }
+}
@@ -0,0 +1,130 @@
+//! Entries archived from stdin or output of commands instead of files on disk.
+//!
+//! Tar headers hold the size of entry data, which is not known until a stream ends, so streams
+//! are spooled into a temporary file first, hashing the data on the way
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::archive;
+use crate::create_header;
+use crate::digest::{Checksums, Digests, HashAlg, HashingReader};
+use crate::pipeline::{Archived, Options, Written};
+use crate::walk;
+
+/// Where data of a streamed entry comes from
+pub enum Source {
+    Stdin,
+    /// Shell command, whose stdout is archived
+    Command(String),
+}
+
+/// Entry archived from a stream
+pub struct Stream {
+    /// Path in the archive
+    pub name: PathBuf,
+    pub source: Source,
+}
+
+impl Stream {
+    /// Stream archived as `name` under `prefix`
+    pub fn new(name: &Path, source: Source, prefix: &Path) -> Result<Self> {
+        let name = walk::relative_name(name)
+            .filter(|name| !name.as_os_str().is_empty())
+            .with_context(|| {
+                format!(
+                    "'{}' is not a path inside the archive",
+                    name.as_os_str().to_string_lossy()
+                )
+            })?;
+        if name == Path::new(archive::META) {
+            bail!(
+                "'{}' is reserved for the manifest and can't be used as a stream name",
+                archive::META
+            );
+        }
+        Ok(Stream {
+            name: prefix.join(name),
+            source,
+        })
+    }
+}
+
+/// Parse `NAME=COMMAND` argument of `--command`
+pub fn parse_command(arg: &str) -> Result<(PathBuf, String), String> {
+    match arg.split_once('=') {
+        Some((name, command)) if !name.is_empty() && !command.is_empty() => {
+            Ok((PathBuf::from(name), command.to_owned()))
+        }
+        _ => Err("expected NAME=COMMAND".to_owned()),
+    }
+}
+
+/// Append data of `stream` to the archive
+pub fn append<W: Write>(
+    tar: &mut tar::Builder<W>,
+    stream: &Stream,
+    options: &Options,
+    mtime: u64,
+) -> Result<Written> {
+    let algs = &options.algs;
+    let (checksums, len, spool) = match &stream.source {
+        Source::Stdin => spool(io::stdin().lock(), algs).context("Unable to read stdin")?,
+        Source::Command(command) => {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Unable to run '{}'", command))?;
+            let stdout = child.stdout.take().expect("Command stdout is piped");
+            let spooled = spool(stdout, algs);
+            let status = child.wait()?;
+            let spooled =
+                spooled.with_context(|| format!("Unable to read output of '{}'", command))?;
+            if !status.success() {
+                bail!("Command '{}' failed: {}", command, status);
+            }
+            spooled
+        }
+    };
+    let mut header = create_header(&stream.name, len, mtime)?;
+    let (user, group) = options.ownership.apply(&mut header, false)?;
+    let pax: Vec<_> = [("uname", &user), ("gname", &group)]
+        .into_iter()
+        .filter_map(|(key, name)| Some((key, name.as_deref()?.as_bytes())))
+        .collect();
+    let append = || -> io::Result<()> {
+        if !pax.is_empty() {
+            tar.append_pax_extensions(pax.iter().copied())?;
+        }
+        tar.append_data(&mut header, &stream.name, spool.take(len))
+    };
+    append().with_context(|| {
+        format!(
+            "Unable to archive '{}'",
+            stream.name.as_os_str().to_string_lossy()
+        )
+    })?;
+    Ok(Written {
+        path: stream.name.clone(),
+        archived: Archived::File(checksums),
+        header: Box::new(header),
+        xattrs: vec![],
+        user,
+        group,
+    })
+}
+
+/// Copy `reader` into an anonymous temporary file, returning checksums and length of the data
+/// along with the file positioned at its start
+fn spool<R: Read>(reader: R, algs: &[HashAlg]) -> io::Result<(Checksums, u64, File)> {
+    let mut file = tempfile::tempfile()?;
+    let mut reader = HashingReader::new(reader, Digests::new(algs));
+    io::copy(&mut reader, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    let len = reader.len();
+    Ok((reader.finish(), len, file))
+}
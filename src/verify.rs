@@ -0,0 +1,55 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, Record};
+use crate::digest::Digests;
+use crate::manifest::{display_name, Manifest};
+
+/// Recompute checksums of every file in the archive and compare them with meta.json
+pub fn verify(path: &Path) -> Result<()> {
+    let mut archive = archive::open(path)?;
+    let mut actual: BTreeMap<PathBuf, Record> = BTreeMap::new();
+    let mut meta = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive::normalize(&entry.path()?);
+        if path == Path::new(archive::META) {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            meta = Some(data);
+            continue;
+        }
+        if let Some(record) = Record::read(&mut entry, Digests::all())? {
+            actual.insert(path, record);
+        }
+    }
+
+    let meta = meta.context("Archive does not contain meta.json")?;
+    let expected: BTreeMap<_, _> = Manifest::parse(&meta)?.records()?.into_iter().collect();
+
+    let mut problems = 0;
+    for (path, record) in &expected {
+        let Some(actual) = actual.get(path) else {
+            println!("missing:  {}", display_name(path));
+            problems += 1;
+            continue;
+        };
+        if let Some(mismatch) = record.mismatch(actual) {
+            println!("mismatch: {} ({})", display_name(path), mismatch);
+            problems += 1;
+        }
+    }
+    for path in actual.keys().filter(|p| !expected.contains_key(*p)) {
+        println!("extra:    {}", display_name(path));
+        problems += 1;
+    }
+
+    if problems != 0 {
+        bail!("Verification failed: {} problem(s) found", problems);
+    }
+    println!("OK: {} entries verified", expected.len());
+    Ok(())
+}
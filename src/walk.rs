@@ -0,0 +1,403 @@
+use anyhow::{bail, Context, Result};
+use ignore::gitignore::Gitignore;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+use crate::archive;
+use crate::filter::{self, Filter};
+
+/// Options of input traversal
+#[derive(Default)]
+pub struct WalkOptions {
+    /// Archive contents of symlink targets instead of symlinks themselves
+    pub follow_symlinks: bool,
+    /// Skip FIFOs and device nodes instead of archiving them
+    pub skip_special: bool,
+    /// Exclude and include patterns
+    pub filter: Option<Filter>,
+    /// Skip paths matched by .gitignore, .ignore and .archiverignore files
+    pub respect_ignore_files: bool,
+    /// (device, inode) of the archive being written, which is never archived into itself
+    pub output: Option<(u64, u64)>,
+    /// Canonical path and (device, inode) of an existing output, replaced once the archive is complete
+    pub replaced: Option<(PathBuf, (u64, u64))>,
+    /// Number of leading components removed from archived paths
+    pub strip_components: usize,
+    /// Directory all archived paths are stored under
+    pub prefix: PathBuf,
+    /// Walk directory entries sorted by name instead of in the order they are read
+    pub sort: bool,
+}
+
+/// Input path given on command line
+pub struct Input {
+    /// Path on disk
+    pub path: PathBuf,
+    /// Normalized path in the archive, before `--strip-components` and `--prefix` are applied
+    pub name: PathBuf,
+}
+
+/// Path found during traversal
+pub struct Found {
+    /// Path on disk
+    pub path: PathBuf,
+    /// Path as it is stored in the archive
+    pub name: PathBuf,
+    /// Archived path found earlier which this one is a hard link to
+    pub hard_link: Option<PathBuf>,
+}
+
+struct Walker<'a> {
+    options: &'a WalkOptions,
+    /// (device, inode) of directories currently being walked, used to detect symlink cycles
+    ancestors: HashSet<(u64, u64)>,
+    /// (device, inode) of files with several hard links, mapped to the first archived path
+    inodes: HashMap<(u64, u64), PathBuf>,
+    /// Patterns of ignore files in directories currently being walked, innermost last
+    ignores: Vec<Gitignore>,
+    /// Archived paths, mapped to the path on disk and whether it is a directory
+    archived: HashMap<PathBuf, (PathBuf, bool)>,
+    emit: &'a mut dyn FnMut(Found) -> Result<()>,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self, path: PathBuf, name: PathBuf) -> Result<()> {
+        let meta = metadata(&path, self.options.follow_symlinks)?;
+        let file_type = meta.file_type();
+        let id = (meta.dev(), meta.ino());
+        let stored = self.stored_name(&name);
+        // Excluded directories are not read at all
+        if self.is_excluded(&path, stored.as_deref(), file_type.is_dir()) {
+            return Ok(());
+        }
+
+        if file_type.is_dir() {
+            if !self.ancestors.insert(id) {
+                warn_skipped(&path, "symlink cycle");
+                return Ok(());
+            }
+            let stored = match stored {
+                Some(stored) => self.claim(stored, &path, true)?,
+                None => None,
+            };
+            if let Some(stored) = stored {
+                (self.emit)(Found {
+                    path: path.clone(),
+                    name: stored,
+                    hard_link: None,
+                })?;
+            }
+            let ignores = match self.options.respect_ignore_files {
+                true => filter::read_ignore_files(&path),
+                false => None,
+            };
+            let has_ignores = ignores.is_some();
+            self.ignores.extend(ignores);
+            let mut entries = fs::read_dir(&path)?.collect::<io::Result<Vec<_>>>()?;
+            if self.options.sort {
+                entries.sort_by_key(|entry| entry.file_name());
+            }
+            for entry in entries {
+                self.walk(entry.path(), name.join(entry.file_name()))?;
+            }
+            if has_ignores {
+                self.ignores.pop();
+            }
+            self.ancestors.remove(&id);
+            return Ok(());
+        }
+        if self.options.output == Some(id) || self.is_replaced(&path, id) {
+            warn_skipped(&path, "archive being written");
+            return Ok(());
+        }
+        if file_type.is_socket() {
+            warn_skipped(&path, "sockets can not be archived");
+            return Ok(());
+        }
+        if self.options.skip_special
+            && (file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device())
+        {
+            warn_skipped(&path, "special file");
+            return Ok(());
+        }
+
+        let Some(name) = stored else {
+            return Ok(());
+        };
+        let name = self
+            .claim(name, &path, false)?
+            .expect("Only directories are merged");
+        let mut hard_link = None;
+        if file_type.is_file() && meta.nlink() > 1 {
+            match self.inodes.get(&id) {
+                Some(first) => hard_link = Some(first.clone()),
+                None => {
+                    self.inodes.insert(id, name.clone());
+                }
+            }
+        }
+        (self.emit)(Found {
+            path,
+            name,
+            hard_link,
+        })
+    }
+
+    /// Record that `path` is archived as `name`, failing if something else already is.
+    ///
+    /// Directories archived under the same name are merged, `None` is returned for all but
+    /// the first one, as it is already archived
+    fn claim(&mut self, name: PathBuf, path: &Path, is_dir: bool) -> Result<Option<PathBuf>> {
+        if name == Path::new(archive::META) {
+            bail!(
+                "'{}' can't be archived as '{}', the name is reserved for the manifest",
+                path.as_os_str().to_string_lossy(),
+                archive::META
+            );
+        }
+        match self.archived.get(&name) {
+            Some((_, true)) if is_dir => Ok(None),
+            Some((first, _)) => bail!(
+                "Both '{}' and '{}' would be archived as '{}'",
+                first.as_os_str().to_string_lossy(),
+                path.as_os_str().to_string_lossy(),
+                name.as_os_str().to_string_lossy()
+            ),
+            None => {
+                self.archived
+                    .insert(name.clone(), (path.to_owned(), is_dir));
+                Ok(Some(name))
+            }
+        }
+    }
+
+    /// Path stored in the archive for input path `name`, `None` if all of it is stripped
+    fn stored_name(&self, name: &Path) -> Option<PathBuf> {
+        let stripped: PathBuf = name
+            .components()
+            .skip(self.options.strip_components)
+            .collect();
+        if stripped.as_os_str().is_empty() {
+            return None;
+        }
+        Some(self.options.prefix.join(stripped))
+    }
+
+    /// Whether `path` is the existing output, as opposed to another hard link to it
+    fn is_replaced(&self, path: &Path, id: (u64, u64)) -> bool {
+        self.options
+            .replaced
+            .as_ref()
+            .is_some_and(|(output, output_id)| {
+                *output_id == id && fs::canonicalize(path).is_ok_and(|path| path == *output)
+            })
+    }
+
+    /// Command line patterns, matched against `stored` path, take precedence over ignore files,
+    /// and deeper ignore files over those of parent directories
+    fn is_excluded(&self, path: &Path, stored: Option<&Path>, is_dir: bool) -> bool {
+        let filter = self
+            .options
+            .filter
+            .iter()
+            .zip(stored)
+            .map(|(f, stored)| f.matched(stored, is_dir));
+        let ignores = self.ignores.iter().rev().map(|i| i.matched(path, is_dir));
+        filter
+            .chain(ignores)
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+}
+
+fn warn_skipped(path: &Path, reason: &str) {
+    eprintln!(
+        "Skipping '{}': {}",
+        path.as_os_str().to_string_lossy(),
+        reason
+    );
+}
+
+/// Metadata of path as it should be archived.
+///
+/// When following symlinks, links which can't be followed are archived as is
+pub fn metadata(path: &Path, follow_symlinks: bool) -> std::io::Result<Metadata> {
+    let meta = fs::symlink_metadata(path)?;
+    if follow_symlinks && meta.is_symlink() {
+        return Ok(fs::metadata(path).unwrap_or(meta));
+    }
+    Ok(meta)
+}
+
+/// Resolve input `paths` given relative to `base_dir`, normalizing the paths they are archived under.
+///
+/// Absolute inputs are archived relative to `base_dir` if they are under it, otherwise without
+/// leading `/`. Inputs pointing outside of `base_dir` with `..` are refused
+pub fn resolve_inputs(args: Vec<PathBuf>, base_dir: Option<&Path>) -> Result<Vec<Input>> {
+    args.into_iter()
+        .map(|arg| {
+            let (path, dst) = split_rename(arg, base_dir);
+            let name = match dst {
+                Some(dst) => relative_name(&dst)
+                    .filter(|name| !name.as_os_str().is_empty())
+                    .with_context(|| {
+                        format!(
+                            "Destination '{}' of '{}' is not a path inside the archive",
+                            dst.as_os_str().to_string_lossy(),
+                            path.as_os_str().to_string_lossy()
+                        )
+                    })?,
+                None => {
+                    let relative = base_dir
+                        .and_then(|base_dir| path.strip_prefix(base_dir).ok())
+                        .unwrap_or(&path);
+                    relative_name(relative).with_context(|| {
+                        format!(
+                            "Input '{}' points outside of the base directory, use -C/--base-dir to archive it",
+                            path.as_os_str().to_string_lossy()
+                        )
+                    })?
+                }
+            };
+            let path = match base_dir {
+                Some(base_dir) => base_dir.join(path),
+                None => path,
+            };
+            Ok(Input { path, name })
+        })
+        .collect()
+}
+
+/// Read input paths listed in file at `path`, or in stdin if it is `-`.
+///
+/// Paths are separated by newlines, or by NUL bytes if `null` is set. Empty entries are ignored
+pub fn read_file_list(path: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let mut data = vec![];
+    let read = match path == Path::new("-") {
+        true => io::stdin().lock().read_to_end(&mut data),
+        false => File::open(path).and_then(|mut file| file.read_to_end(&mut data)),
+    };
+    read.with_context(|| {
+        format!(
+            "Unable to read file list '{}'",
+            path.as_os_str().to_string_lossy()
+        )
+    })?;
+    let separator = if null { b'\0' } else { b'\n' };
+    Ok(data
+        .split(|&b| b == separator)
+        .map(|entry| match null {
+            true => entry,
+            false => entry.strip_suffix(b"\r").unwrap_or(entry),
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| PathBuf::from(OsStr::from_bytes(entry)))
+        .collect())
+}
+
+/// Split `src=dst` input into source path and path to archive it as.
+///
+/// Inputs which exist as they are, are never split
+fn split_rename(arg: PathBuf, base_dir: Option<&Path>) -> (PathBuf, Option<PathBuf>) {
+    let bytes = arg.as_os_str().as_bytes();
+    let Some(pos) = bytes.iter().rposition(|&b| b == b'=') else {
+        return (arg, None);
+    };
+    let on_disk = match base_dir {
+        Some(base_dir) => base_dir.join(&arg),
+        None => arg.clone(),
+    };
+    if fs::symlink_metadata(on_disk).is_ok() {
+        return (arg, None);
+    }
+    let src = PathBuf::from(OsStr::from_bytes(&bytes[..pos]));
+    let dst = PathBuf::from(OsStr::from_bytes(&bytes[pos + 1..]));
+    (src, Some(dst))
+}
+
+/// Path normalized lexically and without root, `None` if it points to a parent with `..`
+pub fn relative_name(path: &Path) -> Option<PathBuf> {
+    let mut name = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => name.push(part),
+            Component::ParentDir => {
+                if !name.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(name)
+}
+
+pub fn check_paths(inputs: &[Input]) -> Result<()> {
+    for Input { path, .. } in inputs {
+        if fs::symlink_metadata(path).is_err() {
+            bail!(
+                "File '{}' does not exist!",
+                path.as_os_str().to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Refuse to create archive at `output` if it is one of the inputs itself.
+///
+/// Returns canonical path of the output
+pub fn check_output(inputs: &[Input], output: &Path) -> Result<Option<PathBuf>> {
+    // Output may not exist yet, so only its directory can be canonicalized
+    let dir = match output.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let Some(name) = output.file_name() else {
+        return Ok(None);
+    };
+    let output = fs::canonicalize(dir)?.join(name);
+    // Input directories containing the output are allowed, the walker skips the output itself
+    for Input { path, .. } in inputs {
+        if fs::canonicalize(path).is_ok_and(|path| path == output) {
+            bail!(
+                "Output '{}' is also an input!",
+                output.as_os_str().to_string_lossy()
+            );
+        }
+    }
+    Ok(Some(output))
+}
+
+/// (device, inode) identifying an open file
+pub fn file_id(file: &File) -> std::io::Result<(u64, u64)> {
+    let meta = file.metadata()?;
+    Ok((meta.dev(), meta.ino()))
+}
+
+/// Walk `inputs`, passing every path found to `emit` as soon as it is found.
+///
+/// Directories are passed before their contents
+pub fn resolve_paths(
+    inputs: Vec<Input>,
+    options: &WalkOptions,
+    emit: &mut dyn FnMut(Found) -> Result<()>,
+) -> Result<()> {
+    let mut walker = Walker {
+        options,
+        ancestors: HashSet::new(),
+        inodes: HashMap::new(),
+        ignores: vec![],
+        archived: HashMap::new(),
+        emit,
+    };
+    for input in inputs {
+        walker.walk(input.path, input.name)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Extended attributes, stored in archives as `SCHILY.xattr.*` PAX records.
+//!
+//! POSIX ACLs are kept by Linux in `system.posix_acl_*` attributes and SELinux labels in
+//! `security.selinux`, so all of them are handled the same way
+use std::fs::{self, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tar::Entry;
+
+pub const PAX_PREFIX: &str = "SCHILY.xattr.";
+const ACLS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Extended attributes to archive or restore
+#[derive(Clone, Copy, Default)]
+pub struct XattrOptions {
+    /// All attributes except ACLs, including SELinux labels
+    pub xattrs: bool,
+    pub acls: bool,
+}
+
+impl XattrOptions {
+    fn wanted(self, name: &str) -> bool {
+        if ACLS.contains(&name) {
+            self.acls
+        } else {
+            self.xattrs
+        }
+    }
+}
+
+/// Read extended attributes of `path` selected by `options`
+pub fn read(
+    path: &Path,
+    follow_symlinks: bool,
+    options: XattrOptions,
+) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = vec![];
+    if !options.xattrs && !options.acls {
+        return Ok(xattrs);
+    }
+    let names = match follow_symlinks {
+        true => xattr::list_deref(path),
+        false => xattr::list(path),
+    };
+    let names = match names {
+        Ok(names) => names,
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(xattrs),
+        Err(err) => return Err(err),
+    };
+    for name in names {
+        let Some(name) = name.to_str() else {
+            eprintln!(
+                "Skipping extended attribute '{}' of '{}': name is not valid UTF-8",
+                name.to_string_lossy(),
+                path.as_os_str().to_string_lossy()
+            );
+            continue;
+        };
+        if !options.wanted(name) {
+            continue;
+        }
+        let value = match follow_symlinks {
+            true => xattr::get_deref(path, name)?,
+            false => xattr::get(path, name)?,
+        };
+        // Attribute may be removed between listing and reading it
+        if let Some(value) = value {
+            xattrs.push((name.to_owned(), value));
+        }
+    }
+    // Listing order depends on the filesystem
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+/// Restore extended attributes of an entry unpacked into `dst` from its PAX records.
+///
+/// Failures are reported as warnings, since e.g. SELinux labels can be set only by privileged users
+pub fn restore<R: Read>(entry: &mut Entry<R>, dst: &Path, options: XattrOptions) -> io::Result<()> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+    let mut xattrs = vec![];
+    for extension in extensions {
+        let extension = extension?;
+        let Some(name) = extension
+            .key()
+            .ok()
+            .and_then(|key| key.strip_prefix(PAX_PREFIX))
+        else {
+            continue;
+        };
+        if options.wanted(name) {
+            xattrs.push((name.to_owned(), extension.value_bytes().to_vec()));
+        }
+    }
+    if xattrs.is_empty() {
+        return Ok(());
+    }
+
+    // Only privileged users may set attributes of read-only files, so the owner is allowed
+    // to write until they are set
+    let meta = fs::symlink_metadata(dst)?;
+    let read_only = !meta.is_symlink() && meta.permissions().mode() & 0o200 == 0;
+    if read_only {
+        fs::set_permissions(
+            dst,
+            Permissions::from_mode(meta.permissions().mode() | 0o200),
+        )?;
+    }
+    for (name, value) in xattrs {
+        if let Err(err) = xattr::set(dst, &name, &value) {
+            eprintln!(
+                "Unable to set extended attribute '{}' of '{}': {}",
+                name,
+                dst.display(),
+                err
+            );
+        }
+    }
+    if read_only {
+        fs::set_permissions(dst, meta.permissions())?;
+    }
+    Ok(())
+}